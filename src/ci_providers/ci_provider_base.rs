@@ -1,9 +1,21 @@
-// https://github.com/KnapsackPro/knapsack-pro-js/blob/main/packages/core/src/ci-providers/ci-provider.base.ts
-pub(crate) trait CiProvider {
+/// Values detected from the CI environment, `None` when the CI doesn't provide them
+///
+/// Same as <https://github.com/KnapsackPro/knapsack-pro-js/blob/main/packages/core/src/ci-providers/ci-provider.base.ts>
+pub trait CiProvider: Send + Sync {
+    /// Number of parallel nodes of the build
     fn get_ci_node_total(&self) -> Option<usize>;
+    /// Zero-based index of this node
     fn get_ci_node_index(&self) -> Option<usize>;
+    /// Identifier shared by all nodes of the build
     fn get_ci_node_build_id(&self) -> Option<String>;
+    /// Commit the build runs on
     fn get_commit_hash(&self) -> Option<String>;
+    /// Whether a retried build gets the same split as the original one
     fn is_fixed_queue_split(&self) -> bool;
+    /// Branch the build runs on
     fn get_branch(&self) -> Option<String>;
+    /// How many times the node was retried, zero for its first run
+    fn get_ci_node_retry_count(&self) -> Option<usize> {
+        None
+    }
 }
@@ -1,47 +1,113 @@
 use crate::ci_providers::ci_provider_base::CiProvider;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use tracing::debug;
 
-pub(crate) struct CiProviderWrapper {
+/// Build id of nodes that have none, see [`CiProviderWrapper::get_ci_node_build_id`]
+pub const MISSING_BUILD_ID: &str = "missing-build-id";
+
+/// CI provider with fallbacks to `KNAPSACK_PRO_*` environment variables
+pub struct CiProviderWrapper {
     ci_provider: Box<dyn CiProvider>,
+    // Read once, the build id file could change while the node runs
+    build_id: OnceLock<Result<String, String>>,
 }
 
 impl CiProviderWrapper {
-    pub(crate) fn new(ci_provider: Box<dyn CiProvider>) -> Self {
-        CiProviderWrapper { ci_provider }
+    /// Wraps given provider
+    pub fn new(ci_provider: Box<dyn CiProvider>) -> Self {
+        CiProviderWrapper {
+            ci_provider,
+            build_id: OnceLock::new(),
+        }
     }
 
-    pub(crate) fn get_ci_node_build_id(&self) -> String {
-        self.ci_provider
-            .get_ci_node_build_id()
-            .or_else(|| std::env::var("KNAPSACK_PRO_CI_NODE_BUILD_ID").ok())
-            .unwrap_or("missing-build-id".into())
+    /// Build id, [`MISSING_BUILD_ID`] when [`Self::require_ci_node_build_id`] finds none
+    pub fn get_ci_node_build_id(&self) -> String {
+        self.require_ci_node_build_id()
+            .unwrap_or_else(|_| MISSING_BUILD_ID.into())
+    }
+
+    /// Build id, `KNAPSACK_PRO_CI_NODE_BUILD_ID` or the content of the file named by
+    /// `KNAPSACK_PRO_CI_NODE_BUILD_ID_FILE` when the provider has none, fails when neither is set.
+    /// It is looked up on first use only.
+    pub fn require_ci_node_build_id(&self) -> Result<String> {
+        self.build_id
+            .get_or_init(|| self.find_ci_node_build_id().map_err(|e| format!("{e:#}")))
+            .clone()
+            .map_err(|e| anyhow!(e))
     }
 
-    pub(crate) fn get_ci_node_index(&self) -> Result<usize> {
+    fn find_ci_node_build_id(&self) -> Result<String> {
+        if let Some(build_id) = self.ci_provider.get_ci_node_build_id() {
+            return Ok(build_id);
+        }
+        debug!("CI provider has no build id, using KNAPSACK_PRO_CI_NODE_BUILD_ID");
+        if let Ok(build_id) = std::env::var("KNAPSACK_PRO_CI_NODE_BUILD_ID") {
+            return Ok(build_id);
+        }
+        let path = std::env::var_os("KNAPSACK_PRO_CI_NODE_BUILD_ID_FILE")
+            .map(PathBuf::from)
+            .ok_or_else(|| anyhow!("No build id provided"))?;
+        let build_id = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read build id from [{}]", path.display()))?;
+        match build_id.trim() {
+            "" => bail!("Build id file [{}] is empty", path.display()),
+            build_id => Ok(build_id.to_string()),
+        }
+    }
+
+    /// Node index, `KNAPSACK_PRO_CI_NODE_INDEX` when the provider has none
+    pub fn get_ci_node_index(&self) -> Result<usize> {
         match self.ci_provider.get_ci_node_index() {
-            None => Self::get_ci_node_index_from_env_var(),
+            None => {
+                debug!("CI provider has no node index, using KNAPSACK_PRO_CI_NODE_INDEX");
+                Self::get_ci_node_index_from_env_var()
+            }
             Some(i) => Ok(i),
         }
     }
 
-    pub(crate) fn get_ci_node_total(&self) -> Result<usize> {
+    /// Node total, `KNAPSACK_PRO_CI_NODE_TOTAL` when the provider has none
+    pub fn get_ci_node_total(&self) -> Result<usize> {
         match self.ci_provider.get_ci_node_total() {
-            None => Self::get_ci_node_total_from_env_var(),
+            None => {
+                debug!("CI provider has no node total, using KNAPSACK_PRO_CI_NODE_TOTAL");
+                Self::get_ci_node_total_from_env_var()
+            }
             Some(i) => Ok(i),
         }
     }
 
-    pub(crate) fn is_fixed_queue_split(&self) -> bool {
+    /// Whether a retried build gets the same split as the original one
+    pub fn is_fixed_queue_split(&self) -> bool {
         self.ci_provider.is_fixed_queue_split()
     }
 
-    pub(crate) fn get_branch(&self) -> Result<String> {
+    /// How many times the node was retried, `KNAPSACK_PRO_CI_NODE_RETRY_COUNT` when the provider
+    /// doesn't know, zero when neither does
+    pub fn get_ci_node_retry_count(&self) -> usize {
+        self.ci_provider
+            .get_ci_node_retry_count()
+            .or_else(|| {
+                std::env::var("KNAPSACK_PRO_CI_NODE_RETRY_COUNT")
+                    .ok()?
+                    .parse()
+                    .ok()
+            })
+            .unwrap_or(0)
+    }
+
+    /// Branch of the build, fails when the provider has none
+    pub fn get_branch(&self) -> Result<String> {
         self.ci_provider
             .get_branch()
             .ok_or_else(|| anyhow!("No branch provided"))
     }
 
-    pub(crate) fn get_commit_hash(&self) -> Result<String> {
+    /// Commit of the build, fails when the provider has none
+    pub fn get_commit_hash(&self) -> Result<String> {
         self.ci_provider
             .get_commit_hash()
             .ok_or_else(|| anyhow!("No commit hash provided"))
@@ -67,3 +133,47 @@ impl CiProviderWrapper {
             })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Gives another build id every time it is asked
+    struct ChangingBuildId(AtomicUsize);
+
+    impl CiProvider for ChangingBuildId {
+        fn get_ci_node_total(&self) -> Option<usize> {
+            Some(1)
+        }
+
+        fn get_ci_node_index(&self) -> Option<usize> {
+            Some(0)
+        }
+
+        fn get_ci_node_build_id(&self) -> Option<String> {
+            Some(format!("build-{}", self.0.fetch_add(1, Ordering::SeqCst)))
+        }
+
+        fn get_commit_hash(&self) -> Option<String> {
+            Some("commit_hash".into())
+        }
+
+        fn is_fixed_queue_split(&self) -> bool {
+            true
+        }
+
+        fn get_branch(&self) -> Option<String> {
+            Some("branch".into())
+        }
+    }
+
+    #[test]
+    fn should_find_build_id_once() -> Result<()> {
+        let wrapper = CiProviderWrapper::new(Box::new(ChangingBuildId(AtomicUsize::new(0))));
+
+        assert_eq!(wrapper.require_ci_node_build_id()?, "build-0");
+        assert_eq!(wrapper.get_ci_node_build_id(), "build-0");
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use crate::ci_providers::ci_provider_base::CiProvider;
 
-pub(crate) struct GithubActionsCiProvider;
+/// Reads `GITHUB_*` variables set by GitHub Actions
+pub struct GithubActionsCiProvider;
 
 impl CiProvider for GithubActionsCiProvider {
     fn get_ci_node_total(&self) -> Option<usize> {
@@ -28,4 +29,13 @@ impl CiProvider for GithubActionsCiProvider {
             .ok()
             .or(std::env::var("GITHUB_SHA").ok())
     }
+
+    // Re-running a job keeps GITHUB_RUN_ID and increments the attempt, which starts at 1
+    fn get_ci_node_retry_count(&self) -> Option<usize> {
+        std::env::var("GITHUB_RUN_ATTEMPT")
+            .ok()?
+            .parse::<usize>()
+            .ok()
+            .map(|attempt| attempt.saturating_sub(1))
+    }
 }
@@ -1,3 +1,6 @@
-pub(crate) mod ci_provider_base;
-pub(crate) mod ci_provider_wrapper;
-pub(crate) mod github_actions;
+/// Interface implemented by every CI provider
+pub mod ci_provider_base;
+/// Provider values with fallbacks to `KNAPSACK_PRO_*` environment variables
+pub mod ci_provider_wrapper;
+/// GitHub Actions provider
+pub mod github_actions;
@@ -0,0 +1,420 @@
+use crate::commands::KNAPSACK_ENDPOINT;
+use cargo_nextest_knapsack::granularity::Granularity;
+use cargo_nextest_knapsack::http_client::HttpConfig;
+use cargo_nextest_knapsack::knapsack_client::KnapsackMode;
+use cargo_nextest_knapsack::test_context::{RunIgnored, TestSelection, DEFAULT_SHUTDOWN_TIMEOUT};
+use clap::{
+    ArgAction, ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+#[derive(Parser)]
+#[command(
+    name = "cargo-nextest-knapsack",
+    bin_name = "cargo nextest-knapsack",
+    version,
+    args_conflicts_with_subcommands = true
+)]
+pub(crate) struct Cli {
+    /// Configuration file [default: .config/nextest-knapsack.toml if it exists]
+    #[arg(
+        long,
+        global = true,
+        env = "NEXTEST_KNAPSACK_CONFIG",
+        value_name = "PATH"
+    )]
+    pub(crate) config: Option<PathBuf>,
+    /// Profile of the configuration file, also passed to nextest unless the profile sets
+    /// `nextest-profile` [default: default]
+    #[arg(long, global = true, env = "NEXTEST_PROFILE", value_name = "NAME")]
+    pub(crate) profile: Option<String>,
+    #[command(flatten)]
+    pub(crate) log: LogArgs,
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+    #[command(flatten)]
+    pub(crate) run: RunArgs,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum Command {
+    /// Run tests received from Knapsack Pro queue (default)
+    Run(RunArgs),
+    /// Upload test results recorded in a journal
+    Upload(UploadArgs),
+    /// Print tests this node would receive, without running them
+    Plan(PlanArgs),
+    /// Start local server compatible with Knapsack Pro API
+    Serve(ServeArgs),
+    /// Start self-hosted coordinator handing out tests to nodes run with `--queue-backend coordinator`
+    Coordinator(ServeArgs),
+    /// Show how tests were split between nodes, based on their journals
+    Report(ReportArgs),
+    /// Run several nodes on this machine against a local queue and report how tests were split
+    Simulate(SimulateArgs),
+    /// Print a new unique build id, for CI providers that don't give one to the nodes
+    BuildId(BuildIdArgs),
+}
+
+// Accepted by every command
+#[derive(Args)]
+pub(crate) struct LogArgs {
+    /// Log more details, `-vv` logs everything
+    #[arg(
+        short,
+        long,
+        global = true,
+        action = ArgAction::Count,
+        conflicts_with = "quiet"
+    )]
+    pub(crate) verbose: u8,
+    /// Log only warnings, `-qq` logs only errors
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    pub(crate) quiet: u8,
+    /// Format of the log
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        env = "NEXTEST_KNAPSACK_LOG_FORMAT",
+        default_value_t = LogFormat::Text
+    )]
+    pub(crate) log_format: LogFormat,
+    /// File the log is appended to as well, with at least debug details
+    /// [default: target/nextest-knapsack/nextest-knapsack.log]
+    #[arg(
+        long,
+        global = true,
+        env = "NEXTEST_KNAPSACK_LOG_FILE",
+        value_name = "PATH"
+    )]
+    pub(crate) log_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub(crate) enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// JSON object per line, with spans of the event
+    Json,
+}
+
+#[derive(Args)]
+pub(crate) struct KnapsackArgs {
+    /// Knapsack Pro API endpoint
+    #[arg(long, env = "KNAPSACK_PRO_ENDPOINT", default_value = KNAPSACK_ENDPOINT)]
+    pub(crate) endpoint: String,
+    /// File with Knapsack Pro test suite token, `-` reads it from standard input
+    /// [default: KNAPSACK_PRO_TEST_SUITE_TOKEN environment variable]
+    #[arg(long, env = "KNAPSACK_PRO_TEST_SUITE_TOKEN_FILE", value_name = "PATH")]
+    pub(crate) token_file: Option<PathBuf>,
+    /// Proxy of API requests [default: HTTPS_PROXY, HTTP_PROXY or ALL_PROXY].
+    /// Hosts listed in NO_PROXY are reached directly
+    #[arg(long, env = "NEXTEST_KNAPSACK_PROXY", value_name = "URL")]
+    pub(crate) proxy: Option<String>,
+    /// PEM file with CA certificates trusted in addition to the built-in ones, e.g. of a proxy
+    /// intercepting HTTPS traffic, can be given multiple times
+    #[arg(
+        long = "ca-cert",
+        env = "NEXTEST_KNAPSACK_CA_CERT",
+        value_delimiter = ',',
+        value_name = "PATH"
+    )]
+    pub(crate) ca_certificates: Vec<PathBuf>,
+}
+
+impl KnapsackArgs {
+    pub(crate) fn http_config(&self) -> HttpConfig {
+        HttpConfig::new(self.proxy.clone(), self.ca_certificates.clone())
+    }
+}
+
+// Options of nextest, applied both when tests are discovered and when they are run
+#[derive(Args)]
+pub(crate) struct NextestArgs {
+    /// Run ignored tests
+    #[arg(long, value_enum, default_value_t = RunIgnored::Default)]
+    pub(crate) run_ignored: RunIgnored,
+    /// Distribute only tests matching the filterset, can be given multiple times
+    #[arg(short = 'E', long = "filterset", value_name = "EXPR")]
+    pub(crate) filtersets: Vec<String>,
+    /// Don't distribute tests matching the filterset, can be given multiple times
+    #[arg(long = "exclude-filterset", value_name = "EXPR")]
+    pub(crate) exclude_filtersets: Vec<String>,
+    /// Distribute only tests of the package, can be given multiple times [default: all workspace packages]
+    #[arg(short = 'p', long = "package", value_name = "SPEC")]
+    pub(crate) packages: Vec<String>,
+    /// Don't distribute tests of the workspace package, can be given multiple times
+    #[arg(long, value_name = "SPEC", conflicts_with = "packages")]
+    pub(crate) exclude: Vec<String>,
+    /// Distribute doctests of selected packages as well, they are run with `cargo test --doc`
+    #[arg(long)]
+    pub(crate) doctests: bool,
+    /// Number of retries of failing tests [default: retries of the nextest profile]
+    #[arg(long)]
+    pub(crate) retries: Option<u32>,
+    // Set from the configuration profile
+    #[arg(skip)]
+    pub(crate) nextest_profile: Option<String>,
+}
+
+impl NextestArgs {
+    pub(crate) fn selection(&self) -> TestSelection {
+        TestSelection {
+            packages: self.packages.clone(),
+            exclude_packages: self.exclude.clone(),
+            filtersets: self.filtersets.clone(),
+            exclude_filtersets: self.exclude_filtersets.clone(),
+            doctests: self.doctests,
+        }
+    }
+}
+
+#[derive(Args)]
+pub(crate) struct RunArgs {
+    #[command(flatten)]
+    pub(crate) knapsack: KnapsackArgs,
+    #[command(flatten)]
+    pub(crate) nextest: NextestArgs,
+    /// Knapsack Pro mode used to split tests
+    #[arg(long, value_enum, default_value_t = KnapsackMode::Queue)]
+    pub(crate) mode: KnapsackMode,
+    /// Where batches of tests are taken from
+    #[arg(long, value_enum, default_value_t = QueueBackendKind::Knapsack)]
+    pub(crate) queue_backend: QueueBackendKind,
+    /// URL of the coordinator, required for `--queue-backend coordinator`
+    #[arg(
+        long,
+        env = "NEXTEST_KNAPSACK_COORDINATOR_URL",
+        required_if_eq("queue_backend", "coordinator")
+    )]
+    pub(crate) coordinator_url: Option<String>,
+    /// Directory shared by all nodes, required for `--queue-backend filesystem`
+    #[arg(
+        long,
+        env = "NEXTEST_KNAPSACK_QUEUE_DIR",
+        required_if_eq("queue_backend", "filesystem")
+    )]
+    pub(crate) queue_dir: Option<PathBuf>,
+    /// Journal of previous run or nextest JUnit report (`.xml`) used by `--queue-backend offline`,
+    /// can be given multiple times
+    #[arg(
+        long,
+        env = "NEXTEST_KNAPSACK_TIMINGS",
+        value_delimiter = ',',
+        required_if_eq("queue_backend", "offline")
+    )]
+    pub(crate) timings: Vec<PathBuf>,
+    /// Seconds given to cargo nextest to stop after SIGINT/SIGTERM before it is killed
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_SHUTDOWN_TIMEOUT.as_secs())]
+    pub(crate) shutdown_timeout: u64,
+    /// Knapsack Pro test suite with tests matching the filterset, can be given multiple times.
+    /// Suites are run one after another, each with the token from
+    /// KNAPSACK_PRO_TEST_SUITE_TOKEN_<NAME> or KNAPSACK_PRO_TEST_SUITE_TOKEN_FILE_<NAME>
+    #[arg(long = "suite", value_name = "NAME=FILTERSET")]
+    pub(crate) suites: Vec<SuiteArg>,
+    /// Fail the run when more tests than this passed only after being retried (see --retries)
+    #[arg(long, value_name = "COUNT")]
+    pub(crate) max_flaky: Option<usize>,
+    /// When batches recorded by the previous run of the node are run again instead of taking
+    /// tests from the queue
+    #[arg(
+        long,
+        value_enum,
+        env = "NEXTEST_KNAPSACK_REPLAY",
+        default_value_t = ReplayMode::Auto
+    )]
+    pub(crate) replay: ReplayMode,
+    /// File the node records its batches in, to be kept until a retry of the node, e.g. in a CI
+    /// cache. `-<suite>` is added to its name for suites
+    /// [default: target/nextest-knapsack/batches-<build id>-<node index>.jsonl]
+    #[arg(long, env = "KNAPSACK_PRO_REPLAY_RECORD", value_name = "PATH")]
+    pub(crate) replay_record: Option<PathBuf>,
+    /// Take tests from the queue even without a build id. Nodes of unrelated builds
+    /// of the same commit and branch then share the queue and take each other's tests
+    #[arg(long)]
+    pub(crate) allow_missing_build_id: bool,
+    /// What is queued as a single test
+    #[arg(long, value_enum, default_value_t = Granularity::Test)]
+    pub(crate) granularity: Granularity,
+    /// What happens when Knapsack Pro or the coordinator can't be reached before the first batch
+    #[arg(long, value_enum, default_value_t = FallbackMode::Fail)]
+    pub(crate) fallback: FallbackMode,
+}
+
+// Test suite given with `--suite`
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SuiteArg {
+    pub(crate) name: String,
+    pub(crate) filterset: String,
+}
+
+impl FromStr for SuiteArg {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, filterset) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME=FILTERSET, got [{value}]"))?;
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "suite name [{name}] may only contain letters, digits, `-` and `_`"
+            ));
+        }
+        if filterset.trim().is_empty() {
+            return Err(format!("suite [{name}] has no filterset"));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            filterset: filterset.to_string(),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub(crate) enum QueueBackendKind {
+    /// Knapsack Pro API
+    Knapsack,
+    /// Self-hosted coordinator
+    Coordinator,
+    /// Directory shared by all nodes
+    Filesystem,
+    /// Static split computed from local timings file, without any API
+    Offline,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub(crate) enum ReplayMode {
+    /// When the CI provider or KNAPSACK_PRO_CI_NODE_RETRY_COUNT tells the node is retried
+    /// and its batches were recorded
+    Auto,
+    /// Always, fails when no batches were recorded
+    Always,
+    /// Never, tests are always taken from the queue
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub(crate) enum FallbackMode {
+    /// Run fails
+    Fail,
+    /// Node runs its share of tests split by timings given with --timings, as with
+    /// `--queue-backend offline`
+    Offline,
+}
+
+#[derive(Args)]
+pub(crate) struct UploadArgs {
+    #[command(flatten)]
+    pub(crate) knapsack: KnapsackArgs,
+    /// Journal to upload, defaults to the journal of current CI node
+    #[arg(long)]
+    pub(crate) journal: Option<PathBuf>,
+    /// Upload journal even if it was already uploaded
+    #[arg(long)]
+    pub(crate) force: bool,
+    /// Test suite whose journal of current CI node is uploaded, when `--journal` is not given
+    #[arg(long, value_name = "NAME", conflicts_with = "journal")]
+    pub(crate) suite: Option<String>,
+}
+
+impl Cli {
+    // When run as `cargo nextest-knapsack`, cargo passes subcommand name as first argument.
+    // Matches are returned as well, they tell which values were given explicitly.
+    pub(crate) fn parse_args() -> (Self, ArgMatches) {
+        let mut args = std::env::args_os().collect::<Vec<_>>();
+        if args.get(1).is_some_and(|arg| arg == "nextest-knapsack") {
+            args.remove(1);
+        }
+        let matches = Self::command().get_matches_from(args);
+        match Self::from_arg_matches(&matches) {
+            Ok(cli) => (cli, matches),
+            Err(error) => error.exit(),
+        }
+    }
+}
+
+#[derive(Args)]
+pub(crate) struct PlanArgs {
+    #[command(flatten)]
+    pub(crate) knapsack: KnapsackArgs,
+    #[command(flatten)]
+    pub(crate) nextest: NextestArgs,
+    /// Knapsack Pro mode used to split tests. Queue Mode consumes the queue, so tests
+    /// returned here are not handed out to other nodes anymore
+    #[arg(long, value_enum, default_value_t = KnapsackMode::Regular)]
+    pub(crate) mode: KnapsackMode,
+    /// Format of the printed plan
+    #[arg(long, value_enum, default_value_t = PlanFormat::Json)]
+    pub(crate) format: PlanFormat,
+    /// Write plan to file instead of stdout
+    #[arg(long)]
+    pub(crate) output: Option<PathBuf>,
+    /// Take tests from the queue even without a build id. Nodes of unrelated builds
+    /// of the same commit and branch then share the queue and take each other's tests
+    #[arg(long)]
+    pub(crate) allow_missing_build_id: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub(crate) enum PlanFormat {
+    /// JSON array of tests
+    Json,
+    /// Filterset for `cargo nextest run -E`
+    Filterset,
+}
+
+#[derive(Args)]
+pub(crate) struct ServeArgs {
+    /// Address to listen on, use port 0 to pick a free one
+    #[arg(long, default_value = "127.0.0.1:3000")]
+    pub(crate) address: String,
+    /// Directory where uploaded test timings are stored
+    /// [default: target/nextest-knapsack/<command name>]
+    #[arg(long)]
+    pub(crate) storage: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub(crate) struct SimulateArgs {
+    /// Number of simulated CI nodes
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) nodes: u64,
+    /// Local queue used by nodes, `knapsack` starts a local server compatible with Knapsack Pro API
+    #[arg(long, value_enum, default_value_t = QueueBackendKind::Knapsack)]
+    pub(crate) queue_backend: QueueBackendKind,
+    /// Directory where timings and output of nodes are stored, timings are kept between simulations
+    /// [default: target/nextest-knapsack/simulate]
+    #[arg(long)]
+    pub(crate) storage: Option<PathBuf>,
+    /// Additional arguments passed to `run` of every node
+    #[arg(last = true)]
+    pub(crate) run_args: Vec<String>,
+}
+
+#[derive(Args)]
+pub(crate) struct BuildIdArgs {
+    /// File shared by the nodes, e.g. on a network drive. The id is written to it unless
+    /// it exists, then the id it holds is printed, so every node prints the same one
+    #[arg(long, value_name = "PATH")]
+    pub(crate) file: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub(crate) struct ReportArgs {
+    /// Journals of nodes of a single build, e.g. collected from CI artifacts
+    /// [default: journals of the latest build in target/nextest-knapsack]
+    pub(crate) journals: Vec<PathBuf>,
+    /// Number of slowest tests to show
+    #[arg(long, default_value_t = 10)]
+    pub(crate) slowest: usize,
+    /// Write report to file instead of stdout, as JSON when the path ends with `.json`.
+    /// Can be given multiple times
+    #[arg(long = "output", value_name = "PATH")]
+    pub(crate) outputs: Vec<PathBuf>,
+}
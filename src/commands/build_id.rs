@@ -0,0 +1,60 @@
+use crate::cli::BuildIdArgs;
+use anyhow::Context;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+pub(crate) fn build_id(args: BuildIdArgs) -> anyhow::Result<()> {
+    let build_id = uuid::Uuid::new_v4().to_string();
+    let build_id = match args.file {
+        Some(path) => shared(&path, build_id)?,
+        None => build_id,
+    };
+    println!("{build_id}");
+    Ok(())
+}
+
+// Id of the first node to link its file wins, the others read it. Linking never replaces
+// an existing file and the linked file is complete, so no node reads a partial id.
+fn shared(path: &Path, build_id: String) -> anyhow::Result<String> {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory [{}]", parent.display()))?;
+    }
+    let temporary = path.with_extension(format!("tmp-{build_id}"));
+    fs::write(&temporary, &build_id)
+        .with_context(|| format!("Failed to write [{}]", temporary.display()))?;
+    let linked = fs::hard_link(&temporary, path);
+    let _ = fs::remove_file(&temporary);
+
+    match linked {
+        Ok(()) => Ok(build_id),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            let existing = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read build id from [{}]", path.display()))?;
+            Ok(existing.trim().to_string())
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to write [{}]", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_share_id_of_first_node() -> anyhow::Result<()> {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let path = directory.join("build-id");
+
+        assert_eq!(shared(&path, "first".into())?, "first");
+        assert_eq!(shared(&path, "second".into())?, "first");
+        assert_eq!(fs::read_dir(&directory)?.count(), 1);
+
+        fs::remove_dir_all(directory)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,14 @@
+use crate::cli::ServeArgs;
+use cargo_nextest_knapsack::server::coordinator::Coordinator;
+use cargo_nextest_knapsack::server::HttpServer;
+use cargo_nextest_knapsack::test_context::KNAPSACK_DIRECTORY;
+use std::path::Path;
+
+pub(crate) fn coordinator(args: ServeArgs) -> anyhow::Result<()> {
+    let storage = args
+        .storage
+        .unwrap_or_else(|| Path::new(KNAPSACK_DIRECTORY).join("coordinator"));
+    let server = HttpServer::bind(&args.address, Coordinator::new(&storage)?)?;
+    println!("Listening on {}", server.base_url()?);
+    server.serve()
+}
@@ -0,0 +1,118 @@
+use crate::cli::KnapsackArgs;
+use anyhow::Context;
+use cargo_nextest_knapsack::ci_providers::ci_provider_base::CiProvider;
+use cargo_nextest_knapsack::ci_providers::ci_provider_wrapper::{
+    CiProviderWrapper, MISSING_BUILD_ID,
+};
+use cargo_nextest_knapsack::ci_providers::github_actions::GithubActionsCiProvider;
+use cargo_nextest_knapsack::failure::{Classify, Failure};
+use cargo_nextest_knapsack::knapsack_client::token::TestSuiteToken;
+use cargo_nextest_knapsack::knapsack_client::KnapsackClient;
+use std::path::PathBuf;
+use tracing::warn;
+
+pub(crate) mod build_id;
+pub(crate) mod coordinator;
+pub(crate) mod plan;
+pub(crate) mod report;
+pub(crate) mod run;
+pub(crate) mod serve;
+pub(crate) mod simulate;
+pub(crate) mod upload;
+
+pub(crate) const KNAPSACK_ENDPOINT: &str = "https://api.knapsackpro.com";
+
+// Token is checked before anything slow (e.g. building the workspace) is done
+pub(crate) fn test_suite_token(
+    args: &KnapsackArgs,
+    suite: Option<&str>,
+    ci_provider: Box<dyn CiProvider>,
+) -> anyhow::Result<TestSuiteToken> {
+    let token = load_test_suite_token(args, suite)?;
+    validate_test_suite_token(args, suite, &token, ci_provider)?;
+    Ok(token)
+}
+
+// Token of a named suite is taken from variables suffixed with its name,
+// e.g. KNAPSACK_PRO_TEST_SUITE_TOKEN_UNIT
+pub(crate) fn load_test_suite_token(
+    args: &KnapsackArgs,
+    suite: Option<&str>,
+) -> anyhow::Result<TestSuiteToken> {
+    match suite {
+        None => load_token(
+            args.token_file.clone(),
+            "KNAPSACK_PRO_TEST_SUITE_TOKEN",
+            "--token-file",
+        ),
+        Some(suite) => {
+            let suffix = suite.to_uppercase().replace('-', "_");
+            load_token(
+                std::env::var_os(format!("KNAPSACK_PRO_TEST_SUITE_TOKEN_FILE_{suffix}"))
+                    .map(PathBuf::from),
+                &format!("KNAPSACK_PRO_TEST_SUITE_TOKEN_{suffix}"),
+                &format!("KNAPSACK_PRO_TEST_SUITE_TOKEN_FILE_{suffix}"),
+            )
+            .with_context(|| format!("Failed to get token of test suite [{suite}]"))
+        }
+    }
+}
+
+pub(crate) fn validate_test_suite_token(
+    args: &KnapsackArgs,
+    suite: Option<&str>,
+    token: &TestSuiteToken,
+    ci_provider: Box<dyn CiProvider>,
+) -> anyhow::Result<()> {
+    let client = KnapsackClient::without_test_context(
+        args.endpoint.clone(),
+        token.clone(),
+        CiProviderWrapper::new(ci_provider),
+    )
+    .with_http_config(args.http_config());
+    match suite {
+        Some(suite) => client
+            .validate_token()
+            .with_context(|| format!("Failed to check token of test suite [{suite}]")),
+        None => client.validate_token(),
+    }
+}
+
+// Nodes without a build id would share `missing-build-id`, so unrelated builds of the same commit
+// and branch would take tests from the same queue. Only called for shared queues, where it warns
+// once when the build id is missing but allowed.
+pub(crate) fn check_build_id(allow_missing: bool) -> anyhow::Result<()> {
+    let build_id =
+        CiProviderWrapper::new(Box::new(GithubActionsCiProvider {})).require_ci_node_build_id();
+    if allow_missing {
+        if let Err(e) = build_id {
+            warn!(
+                "{e:#}, using [{MISSING_BUILD_ID}], nodes of unrelated builds of the same commit and branch share the queue"
+            );
+        }
+        return Ok(());
+    }
+    build_id
+        .context(
+            "Queue needs an id shared only by nodes of the same build, \
+            set KNAPSACK_PRO_CI_NODE_BUILD_ID or KNAPSACK_PRO_CI_NODE_BUILD_ID_FILE \
+            (see `cargo nextest-knapsack build-id`) or pass --allow-missing-build-id",
+        )
+        .classify(Failure::Configuration)?;
+    Ok(())
+}
+
+fn load_token(
+    token_file: Option<PathBuf>,
+    variable: &str,
+    file_option: &str,
+) -> anyhow::Result<TestSuiteToken> {
+    match token_file {
+        Some(path) => TestSuiteToken::load(&path),
+        None => TestSuiteToken::new(std::env::var(variable).with_context(|| {
+            format!("Could not find {variable} environment variable or {file_option}")
+        })?)
+        .with_context(|| format!("Invalid {variable} environment variable")),
+    }
+    .classify(Failure::Configuration)
+}
@@ -0,0 +1,55 @@
+use crate::cli::{PlanArgs, PlanFormat};
+use crate::commands::{check_build_id, test_suite_token};
+use anyhow::Context;
+use cargo_nextest_knapsack::ci_providers::ci_provider_wrapper::CiProviderWrapper;
+use cargo_nextest_knapsack::ci_providers::github_actions::GithubActionsCiProvider;
+use cargo_nextest_knapsack::knapsack_client::{KnapsackClient, KnapsackMode};
+use cargo_nextest_knapsack::models::Test;
+use cargo_nextest_knapsack::queue_backend::QueueBackend;
+use cargo_nextest_knapsack::test_context::DefaultTestContext;
+use std::path::Path;
+use tracing::info;
+
+pub(crate) fn plan(args: PlanArgs) -> anyhow::Result<()> {
+    if args.mode == KnapsackMode::Queue {
+        check_build_id(args.allow_missing_build_id)?;
+    }
+    let token = test_suite_token(&args.knapsack, None, Box::new(GithubActionsCiProvider {}))?;
+
+    info!("Caching workspace info");
+    let context = DefaultTestContext::new(Path::new("."), &args.nextest.selection())?
+        .with_run_ignored(args.nextest.run_ignored)
+        .with_nextest_profile(args.nextest.nextest_profile.clone());
+    info!("Workspace info cached");
+    let ci_provider_wrapper = CiProviderWrapper::new(Box::new(GithubActionsCiProvider {}));
+
+    let http_config = args.knapsack.http_config();
+    let mut client =
+        KnapsackClient::new(args.knapsack.endpoint, token, &context, ci_provider_wrapper)
+            .with_mode(args.mode)
+            .with_http_config(http_config);
+
+    let mut tests = vec![];
+    loop {
+        let mut batch = client.get_tests()?;
+        if batch.is_empty() {
+            break;
+        }
+        tests.append(&mut batch);
+    }
+
+    let output = match args.format {
+        PlanFormat::Json => {
+            serde_json::to_string_pretty(&tests).context("Failed to serialize tests")?
+        }
+        PlanFormat::Filterset => Test::to_nextest_filterset(&tests),
+    };
+
+    match args.output {
+        Some(path) => std::fs::write(&path, output)
+            .with_context(|| format!("Failed to write plan to [{}]", path.display()))?,
+        None => println!("{}", output),
+    }
+
+    Ok(())
+}
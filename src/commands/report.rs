@@ -0,0 +1,422 @@
+use crate::cli::ReportArgs;
+use anyhow::Context;
+use cargo_nextest_knapsack::journal::{Journal, RecordedJournal};
+use cargo_nextest_knapsack::test_context::KNAPSACK_DIRECTORY;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::info;
+
+struct NodeSummary {
+    node_index: usize,
+    tests: usize,
+    test_time: f64,
+    // Wall time of batches, test time for journals without it
+    busy_time: f64,
+    batches: usize,
+}
+
+struct SplitReport {
+    node_total: usize,
+    nodes: Vec<NodeSummary>,
+    // Longest first
+    tests: Vec<(String, f64)>,
+    // Tests that passed only after retries, with the attempt they passed on
+    flaky: Vec<(String, u32)>,
+}
+
+pub(crate) fn report(args: ReportArgs) -> anyhow::Result<()> {
+    let paths = if args.journals.is_empty() {
+        latest_build_journals(Path::new(KNAPSACK_DIRECTORY))?
+    } else {
+        args.journals
+    };
+
+    let mut journals = BTreeMap::new();
+    for path in &paths {
+        let journal = Journal::read(path)?;
+        // Journal of a retried node replaces the previous one
+        journals.insert(
+            (journal.node.node_index, journal.node.suite.clone()),
+            journal,
+        );
+    }
+    let journals = merge_suites(journals.into_values());
+    let report = SplitReport::new(&journals).context("No journals to report on")?;
+    if args.outputs.is_empty() {
+        print!("{}", report.text(args.slowest));
+    }
+    for path in &args.outputs {
+        let content = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => serde_json::to_string_pretty(&report.json(args.slowest))?,
+            _ => report.text(args.slowest),
+        };
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory [{}]", parent.display()))?;
+        }
+        fs::write(path, content)
+            .with_context(|| format!("Failed to write report [{}]", path.display()))?;
+    }
+
+    Ok(())
+}
+
+// Suites are run one after another on a node, so their journals are reported as one
+fn merge_suites(journals: impl Iterator<Item = RecordedJournal>) -> Vec<RecordedJournal> {
+    let mut nodes: BTreeMap<usize, RecordedJournal> = BTreeMap::new();
+    for journal in journals {
+        match nodes.get_mut(&journal.node.node_index) {
+            Some(node) => {
+                node.results.extend(journal.results);
+                node.batches += journal.batches;
+                node.busy_time += journal.busy_time;
+                node.uploaded &= journal.uploaded;
+            }
+            None => {
+                nodes.insert(journal.node.node_index, journal);
+            }
+        }
+    }
+    nodes.into_values().collect()
+}
+
+// Journals of the build whose journal was modified most recently
+fn latest_build_journals(directory: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let entries = fs::read_dir(directory)
+        .with_context(|| format!("Failed to read directory [{}]", directory.display()))?;
+
+    let mut journals = vec![];
+    for entry in entries {
+        let path = entry.context("Failed to read directory entry")?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if !name.starts_with("journal-") || !name.ends_with(".jsonl") {
+            continue;
+        }
+        let modified = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let build_id = Journal::read(&path)?.node.node_build_id;
+        journals.push((path, build_id, modified));
+    }
+
+    let latest_build_id = journals
+        .iter()
+        .max_by_key(|(_, _, modified)| *modified)
+        .map(|(_, build_id, _)| build_id.clone())
+        .with_context(|| format!("No journals found in [{}]", directory.display()))?;
+    info!("Reporting on build [{latest_build_id}]");
+
+    Ok(journals
+        .into_iter()
+        .filter(|(_, build_id, _)| *build_id == latest_build_id)
+        .map(|(path, _, _)| path)
+        .collect())
+}
+
+impl SplitReport {
+    fn new(journals: &[RecordedJournal]) -> Option<Self> {
+        let node_total = journals.first()?.node.node_total;
+        let nodes = journals
+            .iter()
+            .map(|journal| {
+                let test_time = journal
+                    .results
+                    .iter()
+                    .map(|result| result.exec_time)
+                    .sum::<f64>();
+                NodeSummary {
+                    node_index: journal.node.node_index,
+                    tests: journal.results.len(),
+                    test_time,
+                    busy_time: if journal.busy_time > 0.0 {
+                        journal.busy_time
+                    } else {
+                        test_time
+                    },
+                    batches: journal.batches,
+                }
+            })
+            .collect();
+        let mut tests = journals
+            .iter()
+            .flat_map(|journal| &journal.results)
+            .map(|result| (result.test.to_knapsack_file(), result.exec_time))
+            .collect::<Vec<_>>();
+        tests.sort_by(|(a_path, a_time), (b_path, b_time)| {
+            b_time.total_cmp(a_time).then_with(|| a_path.cmp(b_path))
+        });
+        let mut flaky = journals
+            .iter()
+            .flat_map(|journal| &journal.results)
+            .filter(|result| result.is_flaky())
+            .map(|result| (result.test.to_knapsack_file(), result.attempt))
+            .collect::<Vec<_>>();
+        flaky.sort();
+        Some(Self {
+            node_total,
+            nodes,
+            tests,
+            flaky,
+        })
+    }
+
+    fn longest_busy_time(&self) -> f64 {
+        self.nodes
+            .iter()
+            .map(|node| node.busy_time)
+            .fold(0.0, f64::max)
+    }
+
+    fn total_busy_time(&self) -> f64 {
+        self.nodes.iter().map(|node| node.busy_time).sum()
+    }
+
+    // Time the node waited for the slowest node to finish
+    fn idle_time(&self, node: &NodeSummary) -> f64 {
+        self.longest_busy_time() - node.busy_time
+    }
+
+    fn imbalance(&self) -> f64 {
+        let average = self.total_busy_time() / self.nodes.len() as f64;
+        if average > 0.0 {
+            self.longest_busy_time() / average
+        } else {
+            1.0
+        }
+    }
+
+    // Build can't be shorter than its slowest test, nor than a single nextest invocation
+    fn critical_path(&self) -> f64 {
+        let slowest_test = self.tests.first().map_or(0.0, |(_, time)| *time);
+        let shortest_batch = self
+            .nodes
+            .iter()
+            .filter(|node| node.batches > 0)
+            .map(|node| node.busy_time / node.batches as f64)
+            .fold(f64::INFINITY, f64::min);
+        if shortest_batch.is_finite() {
+            slowest_test.max(shortest_batch)
+        } else {
+            slowest_test
+        }
+    }
+
+    // With more nodes than this, some of them only wait for the critical path
+    fn suggested_node_count(&self) -> usize {
+        let critical_path = self.critical_path();
+        if critical_path > 0.0 {
+            ((self.total_busy_time() / critical_path).ceil() as usize).max(1)
+        } else {
+            1
+        }
+    }
+
+    fn estimated_build_time(&self, node_count: usize) -> f64 {
+        (self.total_busy_time() / node_count as f64).max(self.critical_path())
+    }
+
+    // `fmt::Write` into a `String` never fails
+    fn text(&self, slowest: usize) -> String {
+        let mut text = String::new();
+        if self.nodes.len() != self.node_total {
+            writeln!(
+                text,
+                "Warning: found journals of {} out of {} nodes",
+                self.nodes.len(),
+                self.node_total
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            text,
+            "{:>4}  {:>10}  {:>10}  {:>10}  {:>7}  {:>6}",
+            "Node", "Test time", "Busy time", "Idle time", "Batches", "Tests"
+        )
+        .unwrap();
+        for node in &self.nodes {
+            writeln!(
+                text,
+                "{:>4}  {:>10}  {:>10}  {:>10}  {:>7}  {:>6}",
+                node.node_index,
+                format!("{:.1}s", node.test_time),
+                format!("{:.1}s", node.busy_time),
+                format!("{:.1}s", self.idle_time(node)),
+                node.batches,
+                node.tests
+            )
+            .unwrap();
+        }
+        writeln!(
+            text,
+            "Imbalance (slowest / average busy time): {:.2}",
+            self.imbalance()
+        )
+        .unwrap();
+
+        if !self.tests.is_empty() {
+            writeln!(text, "Slowest tests:").unwrap();
+            for (path, time) in self.tests.iter().take(slowest) {
+                writeln!(text, "{:>10}  {}", format!("{time:.1}s"), path).unwrap();
+            }
+        }
+
+        if !self.flaky.is_empty() {
+            writeln!(text, "Flaky tests (passed only after retries):").unwrap();
+            for (path, attempt) in &self.flaky {
+                writeln!(text, "{:>10}  {}", format!("attempt {attempt}"), path).unwrap();
+            }
+        }
+
+        let suggested = self.suggested_node_count();
+        writeln!(
+            text,
+            "Build takes {:.1}s on {} nodes, suggested node count is {} (estimated {:.1}s)",
+            self.longest_busy_time(),
+            self.node_total,
+            suggested,
+            self.estimated_build_time(suggested)
+        )
+        .unwrap();
+        text
+    }
+
+    fn json(&self, slowest: usize) -> serde_json::Value {
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|node| {
+                serde_json::json!({
+                    "node_index": node.node_index,
+                    "tests": node.tests,
+                    "test_time": node.test_time,
+                    "busy_time": node.busy_time,
+                    "idle_time": self.idle_time(node),
+                    "batches": node.batches,
+                })
+            })
+            .collect::<Vec<_>>();
+        let slowest_tests = self
+            .tests
+            .iter()
+            .take(slowest)
+            .map(|(path, time)| serde_json::json!({ "test": path, "exec_time": time }))
+            .collect::<Vec<_>>();
+        let flaky_tests = self
+            .flaky
+            .iter()
+            .map(|(path, attempt)| serde_json::json!({ "test": path, "attempt": attempt }))
+            .collect::<Vec<_>>();
+        let suggested = self.suggested_node_count();
+        serde_json::json!({
+            "node_total": self.node_total,
+            "nodes": nodes,
+            "imbalance": self.imbalance(),
+            "slowest_tests": slowest_tests,
+            "flaky_tests": flaky_tests,
+            "build_time": self.longest_busy_time(),
+            "suggested_node_count": suggested,
+            "estimated_build_time": self.estimated_build_time(suggested),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cargo_nextest_knapsack::journal::JournalNode;
+    use cargo_nextest_knapsack::models::{Test, TestOutcome, TestResult};
+
+    fn journal(node_index: usize, busy_time: f64, times: &[(&str, f64)]) -> RecordedJournal {
+        RecordedJournal {
+            node: JournalNode {
+                commit_hash: "commit_hash".into(),
+                branch: "branch".into(),
+                node_total: 2,
+                node_index,
+                node_build_id: "build_id".into(),
+                fixed_queue_split: true,
+                suite: None,
+            },
+            results: times
+                .iter()
+                .map(|(name, exec_time)| TestResult {
+                    test: Test {
+                        package_name: "pn".into(),
+                        binary_name: "bn".into(),
+                        test_name: name.to_string(),
+                    },
+                    exec_time: *exec_time,
+                    attempt: 1,
+                    outcome: TestOutcome::Passed,
+                    finished_at: None,
+                })
+                .collect(),
+            batches: 1,
+            busy_time,
+            uploaded: true,
+        }
+    }
+
+    #[test]
+    fn should_summarize_split() {
+        let report = SplitReport::new(&[
+            journal(0, 6.0, &[("a", 4.0), ("b", 2.0)]),
+            // Journal without batch wall times falls back to test time
+            journal(1, 0.0, &[("c", 1.0), ("d", 1.0)]),
+        ])
+        .unwrap();
+
+        assert_eq!(report.tests[0], ("pn|bn|a".to_string(), 4.0));
+        assert_eq!(report.idle_time(&report.nodes[0]), 0.0);
+        assert_eq!(report.idle_time(&report.nodes[1]), 4.0);
+        assert_eq!(report.imbalance(), 1.5);
+        // 8s of work, the slowest test takes 4s
+        assert_eq!(report.suggested_node_count(), 2);
+        assert_eq!(report.estimated_build_time(2), 4.0);
+        assert_eq!(report.estimated_build_time(1), 8.0);
+
+        let json = report.json(1);
+        assert_eq!(json["nodes"][1]["idle_time"], 4.0);
+        assert_eq!(json["slowest_tests"].as_array().unwrap().len(), 1);
+        assert_eq!(json["slowest_tests"][0]["test"], "pn|bn|a");
+        assert_eq!(json["suggested_node_count"], 2);
+        assert!(report.text(1).contains("suggested node count is 2"));
+    }
+
+    #[test]
+    fn should_list_flaky_tests() {
+        let mut node = journal(0, 3.0, &[("a", 2.0), ("b", 1.0)]);
+        node.results[1].attempt = 3;
+
+        let report = SplitReport::new(&[node]).unwrap();
+
+        assert_eq!(report.flaky, vec![("pn|bn|b".to_string(), 3)]);
+    }
+
+    #[test]
+    fn should_report_suites_of_node_together() {
+        let mut integration = journal(0, 3.0, &[("c", 3.0)]);
+        integration.node.suite = Some("integration".into());
+
+        let journals = merge_suites(
+            [
+                journal(0, 2.0, &[("a", 2.0)]),
+                integration,
+                journal(1, 1.0, &[("b", 1.0)]),
+            ]
+            .into_iter(),
+        );
+
+        assert_eq!(journals.len(), 2);
+        assert_eq!(journals[0].results.len(), 2);
+        assert_eq!(journals[0].batches, 2);
+        assert_eq!(journals[0].busy_time, 5.0);
+    }
+}
@@ -0,0 +1,368 @@
+use crate::cli::{FallbackMode, QueueBackendKind, ReplayMode, RunArgs};
+use crate::commands::{check_build_id, load_test_suite_token, validate_test_suite_token};
+use anyhow::Context;
+use cargo_nextest_knapsack::ci_providers::ci_provider_wrapper::CiProviderWrapper;
+use cargo_nextest_knapsack::ci_providers::github_actions::GithubActionsCiProvider;
+use cargo_nextest_knapsack::coordinator_client::CoordinatorClient;
+use cargo_nextest_knapsack::failure::{Classify, Failure};
+use cargo_nextest_knapsack::filesystem_queue::FilesystemQueue;
+use cargo_nextest_knapsack::granularity::{Granularity, PerBinary};
+use cargo_nextest_knapsack::journal::{Journal, JournalNode};
+use cargo_nextest_knapsack::knapsack_client::token::TestSuiteToken;
+use cargo_nextest_knapsack::knapsack_client::{KnapsackClient, KnapsackMode};
+use cargo_nextest_knapsack::models::{TestOutcome, TestResult};
+use cargo_nextest_knapsack::offline_split::{load_history, OfflineSplit};
+use cargo_nextest_knapsack::queue_backend::QueueBackend;
+use cargo_nextest_knapsack::replay::{BatchRecord, ReplayQueue};
+use cargo_nextest_knapsack::shutdown;
+use cargo_nextest_knapsack::test_context::{
+    DefaultTestContext, TestContext, TestSelection, KNAPSACK_DIRECTORY,
+};
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, info_span, warn};
+
+// Tests drained from a single queue, `name` is set only for suites given with `--suite`
+struct Suite {
+    name: Option<String>,
+    token: Option<TestSuiteToken>,
+    selection: TestSelection,
+}
+
+pub(crate) fn run(args: RunArgs) -> anyhow::Result<ExitCode> {
+    if args.queue_backend != QueueBackendKind::Knapsack && args.mode == KnapsackMode::Regular {
+        return Err(Failure::Configuration.error(anyhow::anyhow!(
+            "Regular Mode is only supported by Knapsack Pro queue backend"
+        )));
+    }
+    if !args.suites.is_empty() && args.queue_backend != QueueBackendKind::Knapsack {
+        return Err(Failure::Configuration.error(anyhow::anyhow!(
+            "Test suites are only supported by Knapsack Pro queue backend"
+        )));
+    }
+    if !args.suites.is_empty() && args.nextest.doctests {
+        return Err(Failure::Configuration.error(anyhow::anyhow!(
+            "--doctests can't be combined with --suite, filtersets of suites don't apply to doctests"
+        )));
+    }
+    let shared_queue = match args.queue_backend {
+        QueueBackendKind::Knapsack => args.mode == KnapsackMode::Queue,
+        QueueBackendKind::Coordinator | QueueBackendKind::Filesystem => true,
+        QueueBackendKind::Offline => false,
+    };
+    if shared_queue {
+        check_build_id(args.allow_missing_build_id)?;
+    }
+    let suites = suites(&args)?;
+    shutdown::install_handler()?;
+
+    let mut results = vec![];
+    for suite in suites {
+        if shutdown::is_interrupted() {
+            break;
+        }
+        results.append(&mut run_suite(&args, suite)?);
+    }
+
+    if shutdown::is_interrupted() {
+        warn!(
+            "Run was interrupted, uploaded results of {} tests",
+            results.len()
+        );
+        return Ok(ExitCode::from(shutdown::INTERRUPTED_EXIT_CODE));
+    }
+
+    let failed = results
+        .iter()
+        .filter(|result| result.outcome != TestOutcome::Passed)
+        .count();
+    if failed > 0 {
+        return Err(Failure::TestsFailed.error(anyhow::anyhow!("{failed} tests failed")));
+    }
+    let flaky = results.iter().filter(|result| result.is_flaky()).count();
+    if let Some(max_flaky) = args.max_flaky.filter(|max_flaky| flaky > *max_flaky) {
+        return Err(Failure::TestsFailed.error(anyhow::anyhow!(
+            "{flaky} flaky tests passed only after retries, more than --max-flaky {max_flaky}"
+        )));
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+// Tokens of all suites are checked before any of them is run
+fn suites(args: &RunArgs) -> anyhow::Result<Vec<Suite>> {
+    let selection = args.nextest.selection();
+    if args.suites.is_empty() {
+        let token = match args.queue_backend {
+            QueueBackendKind::Knapsack => Some(suite_token(args, None)?),
+            QueueBackendKind::Coordinator
+            | QueueBackendKind::Filesystem
+            | QueueBackendKind::Offline => None,
+        };
+        return Ok(vec![Suite {
+            name: None,
+            token,
+            selection,
+        }]);
+    }
+
+    let mut suites: Vec<Suite> = vec![];
+    for suite in &args.suites {
+        if suites
+            .iter()
+            .any(|other| other.name.as_ref() == Some(&suite.name))
+        {
+            return Err(Failure::Configuration.error(anyhow::anyhow!(
+                "Test suite [{}] is given more than once",
+                suite.name
+            )));
+        }
+        suites.push(Suite {
+            name: Some(suite.name.clone()),
+            token: Some(suite_token(args, Some(&suite.name))?),
+            selection: selection.narrowed_to(&suite.filterset),
+        });
+    }
+    Ok(suites)
+}
+
+// With offline fallback, a token that can't be checked because Knapsack Pro is unreachable is
+// still used, the run falls back once the first batch can't be taken either
+fn suite_token(args: &RunArgs, suite: Option<&str>) -> anyhow::Result<TestSuiteToken> {
+    let token = load_test_suite_token(&args.knapsack, suite)?;
+    match validate_test_suite_token(
+        &args.knapsack,
+        suite,
+        &token,
+        Box::new(GithubActionsCiProvider {}),
+    ) {
+        Err(error) if can_fall_back(args, &error) => {
+            warn!("{error:#}, token is not checked")
+        }
+        result => result?,
+    }
+    Ok(token)
+}
+
+// Only unreachable remote queues fall back, errors of the configuration still fail the run
+fn can_fall_back(args: &RunArgs, error: &anyhow::Error) -> bool {
+    let remote = match args.queue_backend {
+        QueueBackendKind::Knapsack | QueueBackendKind::Coordinator => true,
+        QueueBackendKind::Filesystem | QueueBackendKind::Offline => false,
+    };
+    remote && args.fallback == FallbackMode::Offline && Failure::of(error) == Some(Failure::Api)
+}
+
+// Discovers, runs and uploads tests of the suite, returns uploaded results
+fn run_suite(args: &RunArgs, suite: Suite) -> anyhow::Result<Vec<TestResult>> {
+    let _span = info_span!("suite", name = suite.name).entered();
+    if let Some(name) = &suite.name {
+        info!("Running test suite [{name}]");
+    }
+
+    info!("Caching workspace info");
+    let context = DefaultTestContext::new(Path::new("."), &suite.selection)?
+        .with_shutdown_timeout(Duration::from_secs(args.shutdown_timeout))
+        .with_run_ignored(args.nextest.run_ignored)
+        .with_nextest_profile(args.nextest.nextest_profile.clone())
+        .with_retries(args.nextest.retries);
+    let context: Box<dyn TestContext> = match args.granularity {
+        Granularity::Test => Box::new(context),
+        Granularity::Binary => Box::new(PerBinary::new(context)),
+    };
+    info!("Workspace info cached");
+    let ci_provider_wrapper = CiProviderWrapper::new(Box::new(GithubActionsCiProvider {}));
+
+    let node = JournalNode {
+        suite: suite.name,
+        ..JournalNode::from_ci_provider(&ci_provider_wrapper)?
+    };
+    let mut journal = Journal::create(
+        &Journal::path_for(Path::new(KNAPSACK_DIRECTORY), &node),
+        &node,
+    )?;
+
+    // Decided before the provider is moved into the client
+    let record_path = match &args.replay_record {
+        Some(path) => BatchRecord::path_of_suite(path, &node),
+        None => BatchRecord::path_for(Path::new(KNAPSACK_DIRECTORY), &node),
+    };
+    let replayed = match args.replay {
+        ReplayMode::Never => None,
+        ReplayMode::Always => {
+            Some(BatchRecord::read(&record_path).classify(Failure::Configuration)?)
+        }
+        ReplayMode::Auto => match ci_provider_wrapper.get_ci_node_retry_count() {
+            0 => None,
+            _ if record_path.exists() => Some(BatchRecord::read(&record_path)?),
+            retry_count => {
+                warn!(
+                    "Node is retried ({retry_count} times) but its batches were not recorded at [{}], taking tests from the queue",
+                    record_path.display()
+                );
+                None
+            }
+        },
+    };
+
+    let http_config = args.knapsack.http_config();
+    let mut client: Box<dyn QueueBackend> = match args.queue_backend {
+        QueueBackendKind::Knapsack => Box::new(
+            KnapsackClient::new(
+                args.knapsack.endpoint.clone(),
+                suite.token.context("Knapsack Pro token is required")?,
+                context.as_ref(),
+                ci_provider_wrapper,
+            )
+            .with_mode(args.mode)
+            .with_http_config(http_config),
+        ),
+        QueueBackendKind::Coordinator => Box::new(
+            CoordinatorClient::new(
+                args.coordinator_url
+                    .clone()
+                    .context("Coordinator URL is required for coordinator queue backend")
+                    .classify(Failure::Configuration)?,
+                context.as_ref(),
+                ci_provider_wrapper,
+            )
+            .with_http_config(http_config),
+        ),
+        QueueBackendKind::Filesystem => Box::new(FilesystemQueue::new(
+            args.queue_dir
+                .as_deref()
+                .context("Queue directory is required for filesystem queue backend")
+                .classify(Failure::Configuration)?,
+            context.as_ref(),
+            ci_provider_wrapper,
+        )),
+        QueueBackendKind::Offline => Box::new(OfflineSplit::new(
+            load_history(&args.timings).classify(Failure::Configuration)?,
+            context.as_ref(),
+            ci_provider_wrapper,
+        )),
+    };
+
+    let mut record = match replayed {
+        Some(batches) => {
+            info!(
+                "Replaying {} batches recorded by the previous run of the node",
+                batches.len()
+            );
+            client = Box::new(ReplayQueue::new(batches, client));
+            None
+        }
+        None => Some(BatchRecord::create(&record_path)?),
+    };
+
+    let mut results = vec![];
+    let mut fell_back = false;
+
+    for batch in 1.. {
+        if shutdown::is_interrupted() {
+            break;
+        }
+        let _span = info_span!("batch", number = batch).entered();
+        // Once a batch was taken from the queue, other nodes rely on this node to run it
+        let tests = match client.get_tests() {
+            Err(error) if batch == 1 && record.is_some() && can_fall_back(args, &error) => {
+                warn!("{error:#}, falling back to offline split");
+                client = Box::new(OfflineSplit::new(
+                    load_history(&args.timings).classify(Failure::Configuration)?,
+                    context.as_ref(),
+                    CiProviderWrapper::new(Box::new(GithubActionsCiProvider {})),
+                ));
+                fell_back = true;
+                client.get_tests()?
+            }
+            tests => tests?,
+        };
+        if tests.is_empty() {
+            break;
+        }
+        if let Some(record) = &mut record {
+            record.record(&tests)?;
+        }
+        info!("Received {} tests", tests.len());
+        debug!(tests = ?tests, "Tests of the batch");
+
+        let started = Instant::now();
+        let mut local_results = context.run_tests(&tests).context("Failed to run tests")?;
+        let passed = local_results
+            .iter()
+            .filter(|result| result.outcome == TestOutcome::Passed)
+            .count();
+        info!(
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "Batch finished, {} tests passed ({} flaky), {} failed",
+            passed,
+            local_results
+                .iter()
+                .filter(|result| result.is_flaky())
+                .count(),
+            local_results.len() - passed
+        );
+        journal.record_batch(&local_results, started.elapsed())?;
+        results.append(&mut local_results);
+    }
+
+    client.upload_test_results(&results).with_context(|| match args.queue_backend {
+        QueueBackendKind::Knapsack => format!(
+            "Failed to upload test results, they can be uploaded later with `cargo nextest-knapsack upload --journal {}`",
+            journal.path().display()
+        ),
+        QueueBackendKind::Coordinator
+        | QueueBackendKind::Filesystem
+        | QueueBackendKind::Offline => {
+            "Failed to upload test results".to_string()
+        }
+    })?;
+    if fell_back && args.queue_backend == QueueBackendKind::Knapsack {
+        warn!(
+            "Results of the offline split were not uploaded, they can be uploaded later with `cargo nextest-knapsack upload --journal {}`",
+            journal.path().display()
+        );
+    } else {
+        journal.mark_uploaded()?;
+    }
+
+    let flaky = results
+        .iter()
+        .filter(|result| result.is_flaky())
+        .map(|result| {
+            format!(
+                "{} (passed on attempt {})",
+                result.test.to_knapsack_file(),
+                result.attempt
+            )
+        })
+        .collect::<Vec<_>>();
+    if !flaky.is_empty() {
+        warn!(
+            "{} flaky tests passed only after retries:\n  {}",
+            flaky.len(),
+            flaky.join("\n  ")
+        );
+    }
+    let failed = results
+        .iter()
+        .filter_map(|result| {
+            let outcome = match result.outcome {
+                TestOutcome::Passed => return None,
+                TestOutcome::Failed => "failed",
+                TestOutcome::TimedOut => "timed out",
+            };
+            Some(format!(
+                "{} ({outcome} after {:.1}s)",
+                result.test.to_knapsack_file(),
+                result.exec_time
+            ))
+        })
+        .collect::<Vec<_>>();
+    if !failed.is_empty() {
+        warn!("{} tests failed:\n  {}", failed.len(), failed.join("\n  "));
+    }
+
+    Ok(results)
+}
@@ -0,0 +1,15 @@
+use crate::cli::ServeArgs;
+use cargo_nextest_knapsack::server::knapsack_api::KnapsackApi;
+use cargo_nextest_knapsack::server::HttpServer;
+use cargo_nextest_knapsack::test_context::KNAPSACK_DIRECTORY;
+use std::path::Path;
+
+pub(crate) fn serve(args: ServeArgs) -> anyhow::Result<()> {
+    let storage = args
+        .storage
+        .unwrap_or_else(|| Path::new(KNAPSACK_DIRECTORY).join("server"));
+    let server = HttpServer::bind(&args.address, KnapsackApi::new(&storage)?)?;
+    // First line of output is used by tests to find out the port
+    println!("Listening on {}", server.base_url()?);
+    server.serve()
+}
@@ -0,0 +1,229 @@
+use crate::cli::{QueueBackendKind, SimulateArgs};
+use anyhow::Context;
+use cargo_nextest_knapsack::journal::{Journal, JournalNode, RecordedJournal};
+use cargo_nextest_knapsack::server::coordinator::Coordinator;
+use cargo_nextest_knapsack::server::knapsack_api::KnapsackApi;
+use cargo_nextest_knapsack::server::{HttpServer, RunningServer};
+use cargo_nextest_knapsack::shutdown;
+use cargo_nextest_knapsack::test_context::KNAPSACK_DIRECTORY;
+use clap::ValueEnum;
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitCode, ExitStatus};
+use std::time::{Duration, Instant};
+
+struct SimulatedNode {
+    index: usize,
+    child: Child,
+    log_path: PathBuf,
+    started: Instant,
+    finished: Option<(ExitStatus, Duration)>,
+}
+
+pub(crate) fn simulate(args: SimulateArgs) -> anyhow::Result<ExitCode> {
+    let node_total = args.nodes as usize;
+    let backend_name = args
+        .queue_backend
+        .to_possible_value()
+        .context("Queue backend has no name")?
+        .get_name()
+        .to_string();
+    let storage = args
+        .storage
+        .unwrap_or_else(|| Path::new(KNAPSACK_DIRECTORY).join("simulate"));
+    let backend_storage = storage.join(&backend_name);
+    fs::create_dir_all(&backend_storage)
+        .with_context(|| format!("Failed to create directory [{}]", backend_storage.display()))?;
+    // Nodes receive Ctrl+C as well and stop by themselves, simulation waits for them and reports
+    shutdown::install_handler()?;
+
+    let build_id = format!("simulate-{}", uuid::Uuid::new_v4());
+    let commit_hash = std::env::var("GITHUB_SHA").unwrap_or_else(|_| build_id.clone());
+    let branch = std::env::var("GITHUB_REF").unwrap_or_else(|_| build_id.clone());
+    let mut environment = vec![
+        ("GITHUB_RUN_ID", build_id.clone()),
+        ("GITHUB_SHA", commit_hash.clone()),
+        ("GITHUB_REF", branch.clone()),
+        ("KNAPSACK_PRO_CI_NODE_TOTAL", node_total.to_string()),
+    ];
+
+    // Server is stopped when simulation ends
+    let _server: Option<RunningServer> = match args.queue_backend {
+        QueueBackendKind::Knapsack => {
+            let server =
+                HttpServer::bind("127.0.0.1:0", KnapsackApi::new(&backend_storage)?)?.spawn()?;
+            environment.push(("KNAPSACK_PRO_ENDPOINT", server.base_url().to_string()));
+            environment.push(("KNAPSACK_PRO_TEST_SUITE_TOKEN", "simulate".to_string()));
+            Some(server)
+        }
+        QueueBackendKind::Coordinator => {
+            let server =
+                HttpServer::bind("127.0.0.1:0", Coordinator::new(&backend_storage)?)?.spawn()?;
+            environment.push((
+                "NEXTEST_KNAPSACK_COORDINATOR_URL",
+                server.base_url().to_string(),
+            ));
+            Some(server)
+        }
+        QueueBackendKind::Filesystem => {
+            environment.push((
+                "NEXTEST_KNAPSACK_QUEUE_DIR",
+                backend_storage.display().to_string(),
+            ));
+            None
+        }
+        // Timings file is given with `-- --timings <PATH>`
+        QueueBackendKind::Offline => None,
+    };
+
+    // Tokens (and token files) of this process must not be sent to the local server,
+    // suites get fake tokens instead
+    let parent_tokens = std::env::vars_os()
+        .filter_map(|(name, _)| name.into_string().ok())
+        .filter(|name| name.starts_with("KNAPSACK_PRO_TEST_SUITE_TOKEN"))
+        .collect::<Vec<_>>();
+    let suite_tokens = parent_tokens
+        .iter()
+        .filter_map(|name| {
+            let suite = name.strip_prefix("KNAPSACK_PRO_TEST_SUITE_TOKEN_")?;
+            let suite = match suite.strip_prefix("FILE_") {
+                Some(suite) => suite,
+                None if suite == "FILE" => return None,
+                None => suite,
+            };
+            Some((format!("KNAPSACK_PRO_TEST_SUITE_TOKEN_{suite}"), "simulate"))
+        })
+        .collect::<Vec<_>>();
+
+    let executable = std::env::current_exe().context("Failed to find current executable")?;
+    let mut nodes = vec![];
+    for index in 0..node_total {
+        let log_path = storage.join(format!("node-{index}.log"));
+        let log = File::create(&log_path)
+            .with_context(|| format!("Failed to create [{}]", log_path.display()))?;
+        let mut command = Command::new(&executable);
+        for name in &parent_tokens {
+            command.env_remove(name);
+        }
+        let child = command
+            .args(["run", "--queue-backend", &backend_name])
+            .args(&args.run_args)
+            .envs(suite_tokens.iter().cloned())
+            .envs(environment.iter().map(|(name, value)| (name, value)))
+            .env("KNAPSACK_PRO_CI_NODE_INDEX", index.to_string())
+            .stdout(log.try_clone().context("Failed to duplicate log file")?)
+            .stderr(log)
+            .spawn()
+            .with_context(|| format!("Failed to start node {index}"))?;
+        nodes.push(SimulatedNode {
+            index,
+            child,
+            log_path,
+            started: Instant::now(),
+            finished: None,
+        });
+    }
+    println!(
+        "Started {node_total} nodes with {backend_name} queue backend, output is written to [{}]",
+        storage.display()
+    );
+
+    while nodes.iter().any(|node| node.finished.is_none()) {
+        for node in nodes.iter_mut().filter(|node| node.finished.is_none()) {
+            if let Some(status) = node.child.try_wait().context("Failed to get status")? {
+                node.finished = Some((status, node.started.elapsed()));
+            }
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    let journals = nodes
+        .iter()
+        .map(|node| {
+            let journal_node = JournalNode {
+                commit_hash: commit_hash.clone(),
+                branch: branch.clone(),
+                node_total,
+                node_index: node.index,
+                node_build_id: build_id.clone(),
+                fixed_queue_split: true,
+                suite: None,
+            };
+            Journal::read(&Journal::path_for(
+                Path::new(KNAPSACK_DIRECTORY),
+                &journal_node,
+            ))
+            .ok()
+        })
+        .collect::<Vec<_>>();
+
+    Ok(report(&nodes, &journals))
+}
+
+fn report(nodes: &[SimulatedNode], journals: &[Option<RecordedJournal>]) -> ExitCode {
+    println!(
+        "{:>4}  {:>10}  {:>6}  {:>10}  {:>7}  Status",
+        "Node", "Wall time", "Tests", "Test time", "Batches"
+    );
+
+    let mut wall_times = vec![];
+    // Exit code of the first failed node, so the kind of failure is kept
+    let mut failure = None;
+    for (node, journal) in nodes.iter().zip(journals) {
+        let (status, wall_time) = node.finished.expect("Node has finished");
+        wall_times.push(wall_time.as_secs_f64());
+        let (tests, test_time, batches) = match journal {
+            Some(journal) => (
+                journal.results.len().to_string(),
+                format!(
+                    "{:.1}s",
+                    journal
+                        .results
+                        .iter()
+                        .map(|result| result.exec_time)
+                        .sum::<f64>()
+                ),
+                journal.batches.to_string(),
+            ),
+            None => ("-".into(), "-".into(), "-".into()),
+        };
+        let status = if status.success() {
+            "ok".to_string()
+        } else {
+            failure.get_or_insert(
+                status
+                    .code()
+                    .and_then(|code| u8::try_from(code).ok())
+                    .unwrap_or(1),
+            );
+            format!("failed ({status}), see [{}]", node.log_path.display())
+        };
+        println!(
+            "{:>4}  {:>10}  {:>6}  {:>10}  {:>7}  {}",
+            node.index,
+            format!("{:.1}s", wall_time.as_secs_f64()),
+            tests,
+            test_time,
+            batches,
+            status
+        );
+    }
+
+    let average = wall_times.iter().sum::<f64>() / wall_times.len() as f64;
+    let slowest = wall_times.iter().copied().fold(0.0, f64::max);
+    if average > 0.0 {
+        println!(
+            "Imbalance (slowest / average wall time): {:.2}",
+            slowest / average
+        );
+    }
+
+    if shutdown::is_interrupted() {
+        ExitCode::from(shutdown::INTERRUPTED_EXIT_CODE)
+    } else if let Some(code) = failure {
+        ExitCode::from(code)
+    } else {
+        ExitCode::SUCCESS
+    }
+}
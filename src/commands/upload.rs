@@ -0,0 +1,54 @@
+use crate::cli::UploadArgs;
+use crate::commands::test_suite_token;
+use cargo_nextest_knapsack::ci_providers::ci_provider_wrapper::CiProviderWrapper;
+use cargo_nextest_knapsack::ci_providers::github_actions::GithubActionsCiProvider;
+use cargo_nextest_knapsack::journal::{Journal, JournalNode};
+use cargo_nextest_knapsack::knapsack_client::KnapsackClient;
+use cargo_nextest_knapsack::queue_backend::QueueBackend;
+use cargo_nextest_knapsack::test_context::KNAPSACK_DIRECTORY;
+use std::path::Path;
+use tracing::info;
+
+pub(crate) fn upload(args: UploadArgs) -> anyhow::Result<()> {
+    let path = match args.journal {
+        Some(path) => path,
+        None => {
+            let ci_provider_wrapper = CiProviderWrapper::new(Box::new(GithubActionsCiProvider {}));
+            let node = JournalNode {
+                suite: args.suite,
+                ..JournalNode::from_ci_provider(&ci_provider_wrapper)?
+            };
+            Journal::path_for(Path::new(KNAPSACK_DIRECTORY), &node)
+        }
+    };
+
+    let recorded = Journal::read(&path)?;
+    if recorded.uploaded && !args.force {
+        info!(
+            "Journal [{}] was already uploaded, use --force to upload it again",
+            path.display()
+        );
+        return Ok(());
+    }
+
+    let token = test_suite_token(
+        &args.knapsack,
+        recorded.node.suite.as_deref(),
+        Box::new(recorded.node.clone()),
+    )?;
+    let http_config = args.knapsack.http_config();
+    let client = KnapsackClient::without_test_context(
+        args.knapsack.endpoint,
+        token,
+        CiProviderWrapper::new(Box::new(recorded.node)),
+    )
+    .with_http_config(http_config);
+    client.upload_test_results(&recorded.results)?;
+    info!(
+        "Uploaded {} test results from [{}]",
+        recorded.results.len(),
+        path.display()
+    );
+
+    Journal::open(&path)?.mark_uploaded()
+}
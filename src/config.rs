@@ -0,0 +1,441 @@
+use crate::cli::{
+    FallbackMode, KnapsackArgs, NextestArgs, PlanArgs, QueueBackendKind, ReplayMode, ReportArgs,
+    RunArgs, SuiteArg,
+};
+use anyhow::Context;
+use cargo_nextest_knapsack::granularity::Granularity;
+use cargo_nextest_knapsack::knapsack_client::KnapsackMode;
+use cargo_nextest_knapsack::test_context::RunIgnored;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, ValueEnum};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Next to nextest's `.config/nextest.toml`
+const DEFAULT_CONFIG_PATH: &str = ".config/nextest-knapsack.toml";
+const DEFAULT_PROFILE: &str = "default";
+
+// Content of the configuration file, every profile inherits from `[profile.default]`
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    #[serde(default)]
+    profile: BTreeMap<String, Profile>,
+}
+
+// Settings of a profile, unset ones are taken from the default profile, then from built-in defaults.
+// Paths are relative to the directory nextest-knapsack is run in.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Profile {
+    endpoint: Option<String>,
+    #[serde(default, deserialize_with = "value_enum")]
+    mode: Option<KnapsackMode>,
+    #[serde(default, deserialize_with = "value_enum")]
+    queue_backend: Option<QueueBackendKind>,
+    coordinator_url: Option<String>,
+    queue_dir: Option<PathBuf>,
+    timings: Option<Vec<PathBuf>>,
+    #[serde(default, deserialize_with = "suites")]
+    suites: Option<Vec<SuiteArg>>,
+    proxy: Option<String>,
+    ca_certs: Option<Vec<PathBuf>>,
+    #[serde(default, deserialize_with = "value_enum")]
+    run_ignored: Option<RunIgnored>,
+    filtersets: Option<Vec<String>>,
+    exclude_filtersets: Option<Vec<String>>,
+    packages: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    doctests: Option<bool>,
+    nextest_profile: Option<String>,
+    retries: Option<u32>,
+    shutdown_timeout: Option<u64>,
+    max_flaky: Option<usize>,
+    #[serde(default, deserialize_with = "value_enum")]
+    replay: Option<ReplayMode>,
+    replay_record: Option<PathBuf>,
+    allow_missing_build_id: Option<bool>,
+    #[serde(default, deserialize_with = "value_enum")]
+    granularity: Option<Granularity>,
+    #[serde(default, deserialize_with = "value_enum")]
+    fallback: Option<FallbackMode>,
+    report_outputs: Option<Vec<PathBuf>>,
+}
+
+impl Config {
+    // Configuration from `path`, or from `.config/nextest-knapsack.toml` if it exists
+    pub(crate) fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Path::new(DEFAULT_CONFIG_PATH),
+            None => return Ok(Self::default()),
+        };
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read configuration [{}]", path.display()))?;
+        Self::parse(&content).with_context(|| format!("Invalid configuration [{}]", path.display()))
+    }
+
+    fn parse(content: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    // Profile merged with the default one. Profile that is not configured is not an error,
+    // it may be defined only in nextest configuration.
+    pub(crate) fn profile(&self, name: Option<&str>) -> Profile {
+        let default = self
+            .profile
+            .get(DEFAULT_PROFILE)
+            .cloned()
+            .unwrap_or_default();
+        let profile = match name.and_then(|name| self.profile.get(name)) {
+            Some(profile) => profile.clone().inherit(&default),
+            None => default,
+        };
+        // Profile selected explicitly is passed to nextest, unless the profile names another one
+        Profile {
+            nextest_profile: profile.nextest_profile.or_else(|| name.map(str::to_string)),
+            ..profile
+        }
+    }
+}
+
+impl Profile {
+    fn inherit(self, base: &Profile) -> Profile {
+        Profile {
+            endpoint: self.endpoint.or_else(|| base.endpoint.clone()),
+            mode: self.mode.or(base.mode),
+            queue_backend: self.queue_backend.or(base.queue_backend),
+            coordinator_url: self
+                .coordinator_url
+                .or_else(|| base.coordinator_url.clone()),
+            queue_dir: self.queue_dir.or_else(|| base.queue_dir.clone()),
+            timings: self.timings.or_else(|| base.timings.clone()),
+            suites: self.suites.or_else(|| base.suites.clone()),
+            proxy: self.proxy.or_else(|| base.proxy.clone()),
+            ca_certs: self.ca_certs.or_else(|| base.ca_certs.clone()),
+            run_ignored: self.run_ignored.or(base.run_ignored),
+            filtersets: self.filtersets.or_else(|| base.filtersets.clone()),
+            exclude_filtersets: self
+                .exclude_filtersets
+                .or_else(|| base.exclude_filtersets.clone()),
+            packages: self.packages.or_else(|| base.packages.clone()),
+            exclude: self.exclude.or_else(|| base.exclude.clone()),
+            doctests: self.doctests.or(base.doctests),
+            nextest_profile: self
+                .nextest_profile
+                .or_else(|| base.nextest_profile.clone()),
+            retries: self.retries.or(base.retries),
+            shutdown_timeout: self.shutdown_timeout.or(base.shutdown_timeout),
+            max_flaky: self.max_flaky.or(base.max_flaky),
+            replay: self.replay.or(base.replay),
+            replay_record: self.replay_record.or_else(|| base.replay_record.clone()),
+            allow_missing_build_id: self.allow_missing_build_id.or(base.allow_missing_build_id),
+            granularity: self.granularity.or(base.granularity),
+            fallback: self.fallback.or(base.fallback),
+            report_outputs: self.report_outputs.or_else(|| base.report_outputs.clone()),
+        }
+    }
+
+    pub(crate) fn apply_to_run(&self, args: &mut RunArgs, matches: &ArgMatches) {
+        self.apply_to_knapsack(&mut args.knapsack, matches);
+        self.apply_to_nextest(&mut args.nextest, matches);
+        set(matches, "mode", &mut args.mode, self.mode);
+        set(
+            matches,
+            "queue_backend",
+            &mut args.queue_backend,
+            self.queue_backend,
+        );
+        set(
+            matches,
+            "coordinator_url",
+            &mut args.coordinator_url,
+            self.coordinator_url.clone().map(Some),
+        );
+        set(
+            matches,
+            "queue_dir",
+            &mut args.queue_dir,
+            self.queue_dir.clone().map(Some),
+        );
+        set(matches, "timings", &mut args.timings, self.timings.clone());
+        set(matches, "suites", &mut args.suites, self.suites.clone());
+        set(
+            matches,
+            "shutdown_timeout",
+            &mut args.shutdown_timeout,
+            self.shutdown_timeout,
+        );
+        set(
+            matches,
+            "max_flaky",
+            &mut args.max_flaky,
+            self.max_flaky.map(Some),
+        );
+        set(matches, "replay", &mut args.replay, self.replay);
+        set(
+            matches,
+            "replay_record",
+            &mut args.replay_record,
+            self.replay_record.clone().map(Some),
+        );
+        set(
+            matches,
+            "allow_missing_build_id",
+            &mut args.allow_missing_build_id,
+            self.allow_missing_build_id,
+        );
+        set(
+            matches,
+            "granularity",
+            &mut args.granularity,
+            self.granularity,
+        );
+        set(matches, "fallback", &mut args.fallback, self.fallback);
+    }
+
+    // Mode is not applied, Queue Mode of `run` would make `plan` consume the queue
+    pub(crate) fn apply_to_plan(&self, args: &mut PlanArgs, matches: &ArgMatches) {
+        self.apply_to_knapsack(&mut args.knapsack, matches);
+        self.apply_to_nextest(&mut args.nextest, matches);
+        set(
+            matches,
+            "allow_missing_build_id",
+            &mut args.allow_missing_build_id,
+            self.allow_missing_build_id,
+        );
+    }
+
+    pub(crate) fn apply_to_report(&self, args: &mut ReportArgs, matches: &ArgMatches) {
+        set(
+            matches,
+            "outputs",
+            &mut args.outputs,
+            self.report_outputs.clone(),
+        );
+    }
+
+    pub(crate) fn apply_to_knapsack(&self, args: &mut KnapsackArgs, matches: &ArgMatches) {
+        set(
+            matches,
+            "endpoint",
+            &mut args.endpoint,
+            self.endpoint.clone(),
+        );
+        set(
+            matches,
+            "proxy",
+            &mut args.proxy,
+            self.proxy.clone().map(Some),
+        );
+        set(
+            matches,
+            "ca_certificates",
+            &mut args.ca_certificates,
+            self.ca_certs.clone(),
+        );
+    }
+
+    fn apply_to_nextest(&self, args: &mut NextestArgs, matches: &ArgMatches) {
+        set(
+            matches,
+            "run_ignored",
+            &mut args.run_ignored,
+            self.run_ignored,
+        );
+        set(
+            matches,
+            "filtersets",
+            &mut args.filtersets,
+            self.filtersets.clone(),
+        );
+        set(
+            matches,
+            "exclude_filtersets",
+            &mut args.exclude_filtersets,
+            self.exclude_filtersets.clone(),
+        );
+        set(
+            matches,
+            "packages",
+            &mut args.packages,
+            self.packages.clone(),
+        );
+        set(matches, "exclude", &mut args.exclude, self.exclude.clone());
+        set(matches, "doctests", &mut args.doctests, self.doctests);
+        set(
+            matches,
+            "retries",
+            &mut args.retries,
+            self.retries.map(Some),
+        );
+        args.nextest_profile = self.nextest_profile.clone();
+    }
+}
+
+// Command line and environment variables take precedence over the configuration file
+fn set<T>(matches: &ArgMatches, id: &str, target: &mut T, value: Option<T>) {
+    let explicit = matches!(
+        matches.value_source(id),
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+    );
+    if let (false, Some(value)) = (explicit, value) {
+        *target = value;
+    }
+}
+
+// Same values as accepted on the command line
+fn value_enum<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: ValueEnum,
+{
+    let value = String::deserialize(deserializer)?;
+    T::from_str(&value, false).map(Some).map_err(|_| {
+        let expected = T::value_variants()
+            .iter()
+            .filter_map(|variant| variant.to_possible_value())
+            .map(|variant| format!("`{}`", variant.get_name()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        D::Error::custom(format!(
+            "invalid value `{value}`, expected one of {expected}"
+        ))
+    })
+}
+
+fn suites<'de, D>(deserializer: D) -> Result<Option<Vec<SuiteArg>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|suite| suite.parse().map_err(D::Error::custom))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{Cli, Command};
+    use clap::{CommandFactory, FromArgMatches};
+
+    const CONFIG: &str = r#"
+        [profile.default]
+        endpoint = "http://127.0.0.1:3000"
+        mode = "regular"
+        filtersets = ["kind(test)"]
+        retries = 1
+
+        [profile.ci]
+        mode = "queue"
+        suites = ["unit=not kind(test)"]
+        nextest-profile = "ci-nextest"
+        granularity = "binary"
+        fallback = "offline"
+        report-outputs = ["target/split.json", "target/split.txt"]
+    "#;
+
+    fn run_args(config: &Config, profile: Option<&str>, args: &[&str]) -> RunArgs {
+        let matches = Cli::command()
+            .try_get_matches_from([&["cargo-nextest-knapsack", "run"], args].concat())
+            .unwrap();
+        let Some(Command::Run(mut run)) = Cli::from_arg_matches(&matches).unwrap().command else {
+            panic!("Expected run command");
+        };
+        config
+            .profile(profile)
+            .apply_to_run(&mut run, matches.subcommand().unwrap().1);
+        run
+    }
+
+    #[test]
+    fn should_merge_profiles_with_command_line() {
+        let config = Config::parse(CONFIG).unwrap();
+
+        let default = run_args(&config, None, &[]);
+        assert_eq!(default.knapsack.endpoint, "http://127.0.0.1:3000");
+        assert_eq!(default.mode, KnapsackMode::Regular);
+        assert_eq!(default.nextest.filtersets, vec!["kind(test)"]);
+        assert_eq!(default.nextest.retries, Some(1));
+        assert!(default.suites.is_empty());
+        assert_eq!(default.granularity, Granularity::Test);
+        assert_eq!(default.fallback, FallbackMode::Fail);
+
+        let ci = run_args(&config, Some("ci"), &["-E", "package(a)"]);
+        assert_eq!(ci.knapsack.endpoint, "http://127.0.0.1:3000");
+        assert_eq!(ci.mode, KnapsackMode::Queue);
+        assert_eq!(ci.nextest.filtersets, vec!["package(a)"]);
+        assert_eq!(ci.suites[0].name, "unit");
+        assert_eq!(ci.nextest.nextest_profile.as_deref(), Some("ci-nextest"));
+        assert_eq!(ci.granularity, Granularity::Binary);
+        assert_eq!(ci.fallback, FallbackMode::Offline);
+
+        let ci = run_args(&config, Some("ci"), &["--granularity", "test"]);
+        assert_eq!(ci.granularity, Granularity::Test);
+
+        let nextest_only = run_args(&config, Some("nightly"), &[]);
+        assert_eq!(nextest_only.mode, KnapsackMode::Regular);
+        assert_eq!(
+            nextest_only.nextest.nextest_profile.as_deref(),
+            Some("nightly")
+        );
+    }
+
+    #[test]
+    fn should_apply_report_outputs() {
+        let config = Config::parse(CONFIG).unwrap();
+        let report_args = |profile: Option<&str>, args: &[&str]| {
+            let matches = Cli::command()
+                .try_get_matches_from([&["cargo-nextest-knapsack", "report"], args].concat())
+                .unwrap();
+            let Some(Command::Report(mut report)) =
+                Cli::from_arg_matches(&matches).unwrap().command
+            else {
+                panic!("Expected report command");
+            };
+            config
+                .profile(profile)
+                .apply_to_report(&mut report, matches.subcommand().unwrap().1);
+            report
+        };
+
+        assert!(report_args(None, &[]).outputs.is_empty());
+        assert_eq!(
+            report_args(Some("ci"), &[]).outputs,
+            vec![
+                PathBuf::from("target/split.json"),
+                PathBuf::from("target/split.txt")
+            ]
+        );
+        assert_eq!(
+            report_args(Some("ci"), &["--output", "report.txt"]).outputs,
+            vec![PathBuf::from("report.txt")]
+        );
+    }
+
+    #[test]
+    fn should_reject_unknown_keys_and_values() {
+        let error = Config::parse("[profile.default]\nparallelism = 4\n").unwrap_err();
+        assert!(
+            format!("{error}").contains("unknown field `parallelism`"),
+            "{error}"
+        );
+
+        let error = Config::parse("[profile.default]\nmode = \"fast\"\n").unwrap_err();
+        assert!(
+            format!("{error}").contains("invalid value `fast`, expected one of `queue`, `regular`"),
+            "{error}"
+        );
+
+        let error = Config::parse("[profile.default]\ngranularity = \"file\"\n").unwrap_err();
+        assert!(
+            format!("{error}").contains("invalid value `file`, expected one of `test`, `binary`"),
+            "{error}"
+        );
+    }
+}
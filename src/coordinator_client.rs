@@ -0,0 +1,206 @@
+use crate::ci_providers::ci_provider_wrapper::CiProviderWrapper;
+use crate::failure::{Classify, Failure};
+use crate::http_client::HttpConfig;
+use crate::knapsack_client::api::TestFile;
+use crate::models::{Test, TestResult};
+use crate::queue_backend::QueueBackend;
+use crate::test_context::TestContext;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::debug_span;
+
+/// Takes the next batch of the node, initializes the build when it sends tests
+pub const BATCH_PATH: &str = "/v1/coordinator/batch";
+/// Uploads times of tests executed by the node
+pub const RESULTS_PATH: &str = "/v1/coordinator/results";
+/// Code of [`BatchResponse`] when no node sent tests of the build yet
+pub const BUILD_NOT_INITIALIZED: &str = "BUILD_NOT_INITIALIZED";
+
+/// Worker side of self-hosted coordinator (`cargo nextest-knapsack coordinator`)
+pub struct CoordinatorClient<'a> {
+    endpoint: String,
+    test_context: &'a dyn TestContext,
+    ci_provider_wrapper: CiProviderWrapper,
+    http: HttpConfig,
+}
+
+/// Body of [`BATCH_PATH`] request
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BatchRequest {
+    /// Identifier shared by all nodes of the build
+    pub build_id: String,
+    /// Number of nodes of the build
+    pub node_total: usize,
+    /// Index of the node
+    pub node_index: usize,
+    /// All tests of the build, sent only when the build is initialized
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub test_files: Option<Vec<TestFile>>,
+}
+
+/// Response of [`BATCH_PATH`] request
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BatchResponse {
+    /// [`BUILD_NOT_INITIALIZED`] when the node has to send all tests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    /// Tests for the node, with their expected times
+    #[serde(default)]
+    pub test_files: Vec<TestFile>,
+}
+
+/// Body of [`RESULTS_PATH`] request
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ResultsRequest {
+    /// Tests executed by the node, with their times
+    pub test_files: Vec<TestFile>,
+}
+
+impl CoordinatorClient<'_> {
+    /// Client of the coordinator listening on `endpoint`
+    pub fn new<'a>(
+        endpoint: String,
+        test_context: &'a dyn TestContext,
+        ci_provider_wrapper: CiProviderWrapper,
+    ) -> CoordinatorClient<'a> {
+        CoordinatorClient {
+            endpoint,
+            test_context,
+            ci_provider_wrapper,
+            http: HttpConfig::default(),
+        }
+    }
+
+    /// Sets proxy and CA certificates used to reach the coordinator
+    pub fn with_http_config(self, http: HttpConfig) -> Self {
+        Self { http, ..self }
+    }
+
+    fn get_batch(&self, tests: Option<Vec<Test>>) -> Result<BatchResponse> {
+        let node_total = self
+            .ci_provider_wrapper
+            .get_ci_node_total()
+            .context("Failed to get node total")
+            .classify(Failure::Configuration)?;
+
+        let node_index = self
+            .ci_provider_wrapper
+            .get_ci_node_index()
+            .context("Failed to get node index")
+            .classify(Failure::Configuration)?;
+
+        let request = BatchRequest {
+            build_id: self.ci_provider_wrapper.get_ci_node_build_id(),
+            node_total,
+            node_index,
+            test_files: tests.map(|tests| tests.iter().map(TestFile::from_test).collect()),
+        };
+        self.post(BATCH_PATH, &request, "get tests from coordinator")?
+            .json::<BatchResponse>()
+            .context("Failed to parse response")
+            .classify(Failure::Api)
+    }
+
+    fn post(
+        &self,
+        path: &str,
+        body: &impl Serialize,
+        action: &str,
+    ) -> Result<reqwest::blocking::Response> {
+        let _span = debug_span!("coordinator_request", path).entered();
+        let request = self
+            .http
+            .blocking_client()?
+            .post(format!("{}{}", self.endpoint, path))
+            .json(body);
+        self.http.send(request, action)
+    }
+}
+
+impl QueueBackend for CoordinatorClient<'_> {
+    fn get_tests(&mut self) -> Result<Vec<Test>> {
+        let mut response = self.get_batch(None)?;
+
+        // First node to connect sends the tests it discovered
+        if response.code.as_deref() == Some(BUILD_NOT_INITIALIZED) {
+            let tests = self
+                .test_context
+                .find_tests()
+                .context("Failed to find tests")?;
+            response = self.get_batch(Some(tests))?;
+        }
+
+        let mut files = vec![];
+
+        for file in response.test_files {
+            files.push(
+                Test::from_knapsack_file(&file.path)
+                    .with_context(|| format!("Failed to parse test file: {}", &file.path))?,
+            );
+        }
+
+        Ok(files)
+    }
+
+    fn upload_test_results(&self, test_results: &[TestResult]) -> Result<()> {
+        let request = ResultsRequest {
+            test_files: test_results.iter().map(TestFile::from_result).collect(),
+        };
+        self.post(RESULTS_PATH, &request, "upload test results")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TestOutcome;
+    use crate::server::coordinator::Coordinator;
+    use crate::server::HttpServer;
+    use crate::test_utils::{FixedTests, TestNode};
+    use crate::timings::TimingHistory;
+
+    #[test]
+    fn should_hand_out_all_tests_once() -> Result<()> {
+        let storage = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let server = HttpServer::bind("127.0.0.1:0", Coordinator::new(&storage)?)?.spawn()?;
+        let tests = FixedTests::new(&["a", "b", "c", "d"]);
+
+        let mut nodes = (0..2)
+            .map(|node_index| {
+                CoordinatorClient::new(
+                    server.base_url().into(),
+                    &tests,
+                    CiProviderWrapper::new(Box::new(TestNode::new(node_index, 2))),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut all_tests = vec![];
+        loop {
+            let mut batch = nodes[0].get_tests()?;
+            batch.append(&mut nodes[1].get_tests()?);
+            if batch.is_empty() {
+                break;
+            }
+            all_tests.append(&mut batch);
+        }
+        all_tests.sort();
+        assert_eq!(all_tests, tests.find_tests()?);
+
+        nodes[1].upload_test_results(&[TestResult {
+            test: all_tests[0].clone(),
+            exec_time: 2.5,
+            attempt: 1,
+            outcome: TestOutcome::Passed,
+            finished_at: None,
+        }])?;
+        assert_eq!(
+            TimingHistory::load(&storage.join("timings.json"))?.get("pn|bn|a"),
+            Some(2.5)
+        );
+
+        std::fs::remove_dir_all(storage)?;
+        Ok(())
+    }
+}
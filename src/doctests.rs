@@ -0,0 +1,247 @@
+use crate::failure::Failure;
+use crate::models::{Test, TestOutcome, TestResult};
+use crate::test_context::{RunIgnored, TestSelection};
+use anyhow::Context;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Binary name of doctests. Not a valid cargo target name, so it never clashes with nextest tests.
+pub const DOCTEST_BINARY_NAME: &str = "@doctest";
+
+// rustdoc splits test filters on whitespace, so a single doctest (`src/lib.rs - item (line 3)`)
+// can't be selected. Doctests are distributed per source file instead, selected by its path.
+
+// Workspace packages with a library whose doctests are run by `cargo test --doc`
+pub(crate) fn doctest_packages(
+    cargo_metadata_path: &Path,
+    selection: &TestSelection,
+) -> anyhow::Result<Vec<String>> {
+    let content = fs::read_to_string(cargo_metadata_path).with_context(|| {
+        format!(
+            "Failed to read cargo metadata [{}]",
+            cargo_metadata_path.display()
+        )
+    })?;
+    let metadata: Value =
+        serde_json::from_str(&content).context("Failed to parse cargo metadata")?;
+    let workspace_members = metadata["workspace_members"]
+        .as_array()
+        .context("Cargo metadata has no workspace members")?;
+
+    let mut packages = vec![];
+    for package in metadata["packages"].as_array().into_iter().flatten() {
+        let (Some(name), Some(targets)) = (package["name"].as_str(), package["targets"].as_array())
+        else {
+            continue;
+        };
+        let has_doctests = targets
+            .iter()
+            .any(|target| target["doctest"].as_bool().unwrap_or_default());
+        let selected = if selection.packages.is_empty() {
+            workspace_members.contains(&package["id"])
+                && !selection
+                    .exclude_packages
+                    .iter()
+                    .any(|excluded| excluded == name)
+        } else {
+            selection.packages.iter().any(|selected| selected == name)
+        };
+        if has_doctests && selected {
+            packages.push(name.to_string());
+        }
+    }
+    Ok(packages)
+}
+
+// One test per source file with doctests that would be run with given `--run-ignored`
+pub(crate) fn find_doctests(
+    directory: &Path,
+    package: &str,
+    run_ignored: RunIgnored,
+) -> anyhow::Result<Vec<Test>> {
+    let list = |ignored: bool| -> anyhow::Result<Vec<String>> {
+        let mut command = Command::new("cargo");
+        command
+            .current_dir(directory)
+            .args(["test", "--doc", "--package", package, "--"])
+            .args(["--list", "--format", "terse"]);
+        if ignored {
+            command.arg("--ignored");
+        }
+        let output = command
+            .stderr(Stdio::inherit())
+            .output()
+            .context("Failed to run cargo test --doc")?;
+        // Doctests are compiled when listed
+        if !output.status.success() {
+            return Err(Failure::BuildFailed.error(anyhow::anyhow!(
+                "Failed to list doctests of package [{package}]"
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.strip_suffix(": test"))
+            .map(String::from)
+            .collect())
+    };
+
+    let names = match run_ignored {
+        RunIgnored::Default => {
+            let ignored = list(true)?;
+            list(false)?
+                .into_iter()
+                .filter(|name| !ignored.contains(name))
+                .collect()
+        }
+        RunIgnored::Only => list(true)?,
+        RunIgnored::All => list(false)?,
+    };
+
+    let mut files = BTreeSet::new();
+    for name in &names {
+        let file =
+            doctest_file(name).with_context(|| format!("Unexpected doctest name [{name}]"))?;
+        if file.contains(char::is_whitespace) {
+            anyhow::bail!(
+                "Doctests of [{file}] can't be distributed, its path contains whitespace"
+            );
+        }
+        files.insert(file);
+    }
+
+    Ok(files
+        .into_iter()
+        .map(|file| Test {
+            package_name: package.to_string(),
+            binary_name: DOCTEST_BINARY_NAME.to_string(),
+            test_name: file.to_string(),
+        })
+        .collect())
+}
+
+// Runs doctests of given files, all tests must be from the same package
+pub(crate) fn doctest_command(
+    directory: &Path,
+    package: &str,
+    tests: &[&Test],
+    run_ignored: RunIgnored,
+) -> Command {
+    let mut command = Command::new("cargo");
+    command
+        .current_dir(directory)
+        .args(["test", "--doc", "--package", package, "--"])
+        .args(tests.iter().map(|test| &test.test_name));
+    match run_ignored {
+        RunIgnored::Default => {}
+        RunIgnored::Only => {
+            command.arg("--ignored");
+        }
+        RunIgnored::All => {
+            command.arg("--include-ignored");
+        }
+    }
+    command
+}
+
+// `cargo test --doc` doesn't report time of single doctests, wall time of the run is split
+// evenly between doctests that passed or failed. A file fails when any of its doctests fails.
+pub(crate) fn parse_doctest_results(
+    lines: &[String],
+    tests: &[&Test],
+    wall_time: f64,
+) -> Vec<TestResult> {
+    let tests_by_file = tests
+        .iter()
+        .map(|test| (test.test_name.as_str(), *test))
+        .collect::<HashMap<_, _>>();
+
+    let mut run = BTreeMap::new();
+    for line in lines {
+        let Some((name, outcome)) = line.strip_prefix("test ").and_then(|line| {
+            line.strip_suffix(" ... ok")
+                .map(|name| (name, TestOutcome::Passed))
+                .or_else(|| {
+                    line.strip_suffix(" ... FAILED")
+                        .map(|name| (name, TestOutcome::Failed))
+                })
+        }) else {
+            continue;
+        };
+        // Filter can also match files with longer paths, they are not part of the batch
+        if let Some(test) = doctest_file(name).and_then(|file| tests_by_file.get(file)) {
+            let (count, file_outcome) = run.entry(*test).or_insert((0, TestOutcome::Passed));
+            *count += 1;
+            if outcome != TestOutcome::Passed {
+                *file_outcome = outcome;
+            }
+        }
+    }
+
+    let total = run.values().map(|(count, _)| count).sum::<usize>();
+    run.into_iter()
+        .map(|(test, (count, outcome))| TestResult {
+            test: test.clone(),
+            exec_time: wall_time * count as f64 / total as f64,
+            attempt: 1,
+            outcome,
+            finished_at: None,
+        })
+        .collect()
+}
+
+fn doctest_file(name: &str) -> Option<&str> {
+    name.split_once(" - ").map(|(file, _)| file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doctest(file: &str) -> Test {
+        Test {
+            package_name: "pn".into(),
+            binary_name: DOCTEST_BINARY_NAME.into(),
+            test_name: file.into(),
+        }
+    }
+
+    #[test]
+    fn should_split_wall_time_between_run_doctests() {
+        let lib = doctest("pn/src/lib.rs");
+        let module = doctest("pn/src/module.rs");
+        let lines = [
+            "running 4 tests",
+            "test pn/src/lib.rs - add (line 3) ... ok",
+            "test pn/src/lib.rs - subtract (line 10) ... FAILED",
+            "test pn/src/module.rs - multiply (line 1) ... ok",
+            "test pn/src/module.rs - divide (line 8) ... ignored",
+            "test pn/src/other/pn/src/lib.rs - add (line 3) ... ok",
+        ]
+        .map(String::from);
+
+        let results = parse_doctest_results(&lines, &[&lib, &module], 6.0);
+
+        assert_eq!(
+            results,
+            vec![
+                TestResult {
+                    test: lib.clone(),
+                    exec_time: 4.0,
+                    attempt: 1,
+                    outcome: TestOutcome::Failed,
+                    finished_at: None,
+                },
+                TestResult {
+                    test: module.clone(),
+                    exec_time: 2.0,
+                    attempt: 1,
+                    outcome: TestOutcome::Passed,
+                    finished_at: None,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt;
+
+/// Kind of failure, tells pipelines whether retrying the job may help
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// Invalid options, configuration file, token or CI environment, retrying won't help
+    Configuration,
+    /// Knapsack Pro API, coordinator, shared queue directory or network failed, retrying may help
+    Api,
+    /// Tests failed, retrying won't help
+    TestsFailed,
+    /// Workspace or test binaries failed to build or list their tests
+    BuildFailed,
+}
+
+impl Failure {
+    /// Exit code of the process, `Configuration` uses the code clap exits with on invalid arguments,
+    /// `Api` is `EX_TEMPFAIL` of sysexits and `TestsFailed`/`BuildFailed` are the codes of nextest.
+    /// `BuildFailed` is also the code Rust exits with on panic, the binary exits with 70 instead.
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Failure::Configuration => 2,
+            Failure::Api => 75,
+            Failure::TestsFailed => 100,
+            Failure::BuildFailed => 101,
+        }
+    }
+
+    /// Kind of the error, the outermost one when kinds were attached more than once
+    pub fn of(error: &anyhow::Error) -> Option<Failure> {
+        error
+            .downcast_ref::<Classified>()
+            .map(|classified| classified.failure)
+    }
+
+    /// Attaches this kind to the error, its message and causes are unchanged
+    pub fn error(self, error: impl Into<anyhow::Error>) -> anyhow::Error {
+        anyhow::Error::new(Classified {
+            failure: self,
+            error: error.into(),
+        })
+    }
+}
+
+/// Attaches [`Failure`] kind to the error of a result
+pub trait Classify<T> {
+    /// Error of the result gets given kind
+    fn classify(self, failure: Failure) -> anyhow::Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> Classify<T> for Result<T, E> {
+    fn classify(self, failure: Failure) -> anyhow::Result<T> {
+        self.map_err(|error| failure.error(error))
+    }
+}
+
+// Transparent wrapper, found by `anyhow::Error::downcast_ref` through any context added later
+struct Classified {
+    failure: Failure,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for Classified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl fmt::Debug for Classified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for Classified {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn should_keep_kind_and_message_through_context() {
+        let error = Err::<(), _>(anyhow::anyhow!("Knapsack Pro returned 500"))
+            .classify(Failure::Api)
+            .context("Failed to get tests")
+            .unwrap_err();
+
+        assert_eq!(Failure::of(&error), Some(Failure::Api));
+        assert_eq!(
+            format!("{error:#}"),
+            "Failed to get tests: Knapsack Pro returned 500"
+        );
+
+        let reclassified = Failure::Configuration.error(error);
+        assert_eq!(Failure::of(&reclassified), Some(Failure::Configuration));
+        assert_eq!(Failure::of(&anyhow::anyhow!("other")), None);
+    }
+}
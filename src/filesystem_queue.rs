@@ -0,0 +1,417 @@
+use crate::ci_providers::ci_provider_wrapper::CiProviderWrapper;
+use crate::failure::{Classify, Failure};
+use crate::journal::path_safe;
+use crate::models::{Test, TestResult};
+use crate::queue_backend::QueueBackend;
+use crate::test_context::TestContext;
+use crate::timings::{queue_batches, TimingHistory};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::warn;
+
+// How long nodes wait for the first node to discover tests
+const INITIALIZATION_TIMEOUT: Duration = Duration::from_secs(600);
+// Lock older than this is assumed to be left by a node that crashed
+const STALE_LOCK_AGE: Duration = Duration::from_secs(60);
+// Node discovering tests refreshes its lock this often, discovery may build the workspace
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Serialize, Deserialize)]
+struct QueueFile {
+    node_total: usize,
+    batches: Vec<Vec<String>>,
+}
+
+/// Queue shared by nodes through a directory (e.g. NFS/EFS volume). Layout:
+///
+/// ```text
+/// timings.json            - timings of previous runs, shared between builds
+/// <build id>/init.lock    - created by the node that discovers tests
+/// <build id>/queue.json   - tests split into batches, longest first
+/// <build id>/claims/<n>   - created by the node that took batch n
+/// ```
+///
+/// The directory plays the role of Knapsack Pro API, failing to read or write it is a
+/// [`Failure::Api`], retrying may help.
+pub struct FilesystemQueue<'a> {
+    directory: PathBuf,
+    test_context: &'a dyn TestContext,
+    ci_provider_wrapper: CiProviderWrapper,
+    batches: Option<Vec<Vec<String>>>,
+    next_batch: usize,
+}
+
+impl FilesystemQueue<'_> {
+    /// Queue in `directory`, created by the first node of the build
+    pub fn new<'a>(
+        directory: &Path,
+        test_context: &'a dyn TestContext,
+        ci_provider_wrapper: CiProviderWrapper,
+    ) -> FilesystemQueue<'a> {
+        FilesystemQueue {
+            directory: directory.to_path_buf(),
+            test_context,
+            ci_provider_wrapper,
+            batches: None,
+            next_batch: 0,
+        }
+    }
+
+    fn timings_path(&self) -> PathBuf {
+        self.directory.join("timings.json")
+    }
+
+    fn build_directory(&self) -> PathBuf {
+        self.directory
+            .join(path_safe(&self.ci_provider_wrapper.get_ci_node_build_id()))
+    }
+
+    fn load_or_initialize(&self) -> Result<Vec<Vec<String>>> {
+        let node_total = self
+            .ci_provider_wrapper
+            .get_ci_node_total()
+            .context("Failed to get node total")
+            .classify(Failure::Configuration)?;
+
+        let build_directory = self.build_directory();
+        fs::create_dir_all(build_directory.join("claims"))
+            .with_context(|| format!("Failed to create directory [{}]", build_directory.display()))
+            .classify(Failure::Api)?;
+        let queue_path = build_directory.join("queue.json");
+
+        let init_lock = build_directory.join("init.lock");
+        let deadline = Instant::now() + INITIALIZATION_TIMEOUT;
+        let queue = loop {
+            if create_new(&init_lock)? {
+                let queue = holding(&init_lock, || self.initialize(node_total))?;
+                let content = serde_json::to_string(&queue).context("Failed to serialize queue")?;
+                write_atomically(&queue_path, &content)?;
+                break queue;
+            }
+            if queue_path.exists() {
+                let content = fs::read_to_string(&queue_path)
+                    .with_context(|| format!("Failed to read queue [{}]", queue_path.display()))
+                    .classify(Failure::Api)?;
+                break serde_json::from_str::<QueueFile>(&content)
+                    .with_context(|| format!("Failed to parse queue [{}]", queue_path.display()))
+                    .classify(Failure::Api)?;
+            }
+            if remove_stale(&init_lock)? {
+                warn!(
+                    "Node discovering tests stopped without creating the queue, removed its lock [{}]",
+                    init_lock.display()
+                );
+                continue;
+            }
+            if Instant::now() > deadline {
+                return Err(Failure::Api.error(anyhow::anyhow!(
+                    "Queue [{}] was not initialized within {:?}",
+                    queue_path.display(),
+                    INITIALIZATION_TIMEOUT
+                )));
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+
+        if queue.node_total != node_total {
+            return Err(Failure::Configuration.error(anyhow::anyhow!(
+                "Queue [{}] was created for {} nodes, but this build has {}",
+                queue_path.display(),
+                queue.node_total,
+                node_total
+            )));
+        }
+
+        Ok(queue.batches)
+    }
+
+    fn initialize(&self, node_total: usize) -> Result<QueueFile> {
+        let tests = self
+            .test_context
+            .find_tests()
+            .context("Failed to find tests")?;
+        let paths = tests
+            .iter()
+            .map(|test| test.to_knapsack_file())
+            .collect::<Vec<_>>();
+        let timings = TimingHistory::load(&self.timings_path()).classify(Failure::Api)?;
+        Ok(QueueFile {
+            node_total,
+            batches: queue_batches(timings.longest_first(&paths), node_total)
+                .into_iter()
+                .map(|batch| batch.into_iter().map(|(path, _)| path).collect())
+                .collect(),
+        })
+    }
+}
+
+impl QueueBackend for FilesystemQueue<'_> {
+    fn get_tests(&mut self) -> Result<Vec<Test>> {
+        if self.batches.is_none() {
+            self.batches = Some(self.load_or_initialize()?);
+        }
+        let batches = self.batches.as_ref().unwrap();
+        let claims = self.build_directory().join("claims");
+
+        while self.next_batch < batches.len() {
+            let index = self.next_batch;
+            self.next_batch += 1;
+
+            if create_new(&claims.join(index.to_string()))? {
+                let mut tests = vec![];
+                for path in &batches[index] {
+                    tests.push(
+                        Test::from_knapsack_file(path)
+                            .with_context(|| format!("Failed to parse test file: {}", path))?,
+                    );
+                }
+                return Ok(tests);
+            }
+        }
+
+        Ok(vec![])
+    }
+
+    fn upload_test_results(&self, test_results: &[TestResult]) -> Result<()> {
+        let lock = self.directory.join("timings.lock");
+        while !create_new(&lock)? {
+            if remove_stale(&lock)? {
+                warn!("Removed lock left by a crashed node [{}]", lock.display());
+                continue;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+
+        let result = TimingHistory::load(&self.timings_path()).and_then(|mut timings| {
+            for result in test_results {
+                timings.record(&result.test.to_knapsack_file(), result.exec_time);
+            }
+            timings.save(&self.timings_path())
+        });
+
+        fs::remove_file(&lock)
+            .with_context(|| format!("Failed to remove lock [{}]", lock.display()))
+            .classify(Failure::Api)?;
+        result.classify(Failure::Api)
+    }
+}
+
+// Atomically creates the file, returns false if it already exists
+fn create_new(path: &Path) -> Result<bool> {
+    match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(mut file) => {
+            // Owner of the file is only informative
+            let _ = writeln!(file, "{}", std::process::id());
+            Ok(true)
+        }
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e)
+            .with_context(|| format!("Failed to create [{}]", path.display()))
+            .classify(Failure::Api),
+    }
+}
+
+// Removes the lock if it is stale, returns false if it is not or another node removed it first.
+// Nodes may see the same stale lock, so a node claims it by renaming it to a name only it knows.
+// The lock is checked again after the rename: when another node replaced the stale lock with a
+// fresh one in the meantime, the rename took the fresh one and it is put back.
+fn remove_stale(lock: &Path) -> Result<bool> {
+    if !is_stale(lock) {
+        return Ok(false);
+    }
+    let claimed = lock.with_extension(format!("stale-{}", uuid::Uuid::new_v4()));
+    match fs::rename(lock, &claimed) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Failed to remove lock [{}]", lock.display()))
+                .classify(Failure::Api)
+        }
+    }
+
+    let stale = is_stale(&claimed);
+    if !stale {
+        // Hard link doesn't replace a lock created since, unlike rename
+        match fs::hard_link(&claimed, lock) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to restore lock [{}]", lock.display()))
+                    .classify(Failure::Api)
+            }
+        }
+    }
+    fs::remove_file(&claimed)
+        .with_context(|| format!("Failed to remove lock [{}]", claimed.display()))
+        .classify(Failure::Api)?;
+    Ok(stale)
+}
+
+// Lock that was not refreshed for a while, a lock that no longer exists is not stale
+fn is_stale(lock: &Path) -> bool {
+    fs::metadata(lock)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age > STALE_LOCK_AGE)
+}
+
+// Refreshes the lock while `work` runs, so other nodes don't take it for a lock of a crashed node
+fn holding<T>(lock: &Path, work: impl FnOnce() -> Result<T>) -> Result<T> {
+    let (done, finished) = mpsc::channel::<()>();
+    std::thread::scope(|scope| {
+        scope.spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = finished.recv_timeout(HEARTBEAT_INTERVAL) {
+                let _ = File::options()
+                    .write(true)
+                    .open(lock)
+                    .and_then(|file| file.set_modified(SystemTime::now()));
+            }
+        });
+        let result = work();
+        drop(done);
+        result
+    })
+}
+
+fn write_atomically(path: &Path, content: &str) -> Result<()> {
+    let temporary = path.with_extension(format!("tmp-{}", uuid::Uuid::new_v4()));
+    fs::write(&temporary, content)
+        .and_then(|_| fs::rename(&temporary, path))
+        .with_context(|| format!("Failed to write [{}]", path.display()))
+        .classify(Failure::Api)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TestOutcome;
+    use crate::test_utils::{FixedTests, TestNode};
+
+    #[test]
+    fn should_share_queue_between_nodes() -> Result<()> {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let tests = FixedTests::new(&["a", "b", "c", "d"]);
+
+        let mut nodes = (0..2)
+            .map(|node_index| {
+                FilesystemQueue::new(
+                    &directory,
+                    &tests,
+                    CiProviderWrapper::new(Box::new(TestNode::new(node_index, 2))),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut all_tests = vec![];
+        loop {
+            let mut batch = nodes[1].get_tests()?;
+            batch.append(&mut nodes[0].get_tests()?);
+            if batch.is_empty() {
+                break;
+            }
+            all_tests.append(&mut batch);
+        }
+        all_tests.sort();
+        assert_eq!(all_tests, tests.find_tests()?);
+
+        nodes[0].upload_test_results(&[TestResult {
+            test: all_tests[0].clone(),
+            exec_time: 2.5,
+            attempt: 1,
+            outcome: TestOutcome::Passed,
+            finished_at: None,
+        }])?;
+        nodes[1].upload_test_results(&[TestResult {
+            test: all_tests[1].clone(),
+            exec_time: 1.5,
+            attempt: 1,
+            outcome: TestOutcome::Passed,
+            finished_at: None,
+        }])?;
+        let timings = TimingHistory::load(&directory.join("timings.json"))?;
+        assert_eq!(timings.get("pn|bn|a"), Some(2.5));
+        assert_eq!(timings.get("pn|bn|b"), Some(1.5));
+
+        fs::remove_dir_all(directory)?;
+        Ok(())
+    }
+
+    #[test]
+    fn should_recover_locks_left_by_crashed_node() -> Result<()> {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let tests = FixedTests::new(&["a", "b"]);
+        let mut node = FilesystemQueue::new(
+            &directory,
+            &tests,
+            CiProviderWrapper::new(Box::new(TestNode::new(0, 1))),
+        );
+
+        // Node crashed while discovering tests and another one while merging timings
+        let crashed = SystemTime::now() - STALE_LOCK_AGE * 2;
+        fs::create_dir_all(node.build_directory())?;
+        for lock in [
+            node.build_directory().join("init.lock"),
+            directory.join("timings.lock"),
+        ] {
+            File::create(&lock)?.set_modified(crashed)?;
+        }
+
+        let mut all_tests = node.get_tests()?;
+        all_tests.append(&mut node.get_tests()?);
+        all_tests.sort();
+        assert_eq!(all_tests, tests.find_tests()?);
+
+        node.upload_test_results(&[TestResult {
+            test: all_tests[0].clone(),
+            exec_time: 2.5,
+            attempt: 1,
+            outcome: TestOutcome::Passed,
+            finished_at: None,
+        }])?;
+        let timings = TimingHistory::load(&node.timings_path())?;
+        assert_eq!(timings.get("pn|bn|a"), Some(2.5));
+
+        fs::remove_dir_all(directory)?;
+        Ok(())
+    }
+
+    #[test]
+    fn should_remove_stale_lock_once() -> Result<()> {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&directory)?;
+        let lock = directory.join("timings.lock");
+
+        File::create(&lock)?.set_modified(SystemTime::now() - STALE_LOCK_AGE * 2)?;
+        assert!(remove_stale(&lock)?);
+        assert!(!lock.exists());
+        // Another node saw the same stale lock, but it is gone
+        assert!(!remove_stale(&lock)?);
+
+        // Fresh lock is kept
+        assert!(create_new(&lock)?);
+        assert!(!remove_stale(&lock)?);
+        assert!(lock.exists());
+        assert_eq!(fs::read_dir(&directory)?.count(), 1);
+
+        fs::remove_dir_all(directory)?;
+        Ok(())
+    }
+
+    #[test]
+    fn should_classify_queue_errors() {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let error = write_atomically(&directory.join("queue.json"), "{}").unwrap_err();
+        assert_eq!(Failure::of(&error), Some(Failure::Api));
+    }
+}
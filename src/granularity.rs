@@ -0,0 +1,213 @@
+use crate::models::{Test, TestOutcome, TestResult};
+use crate::test_context::TestContext;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Test name of a whole binary queued as a single test, `package|binary|*` in Knapsack
+pub const BINARY_TEST_NAME: &str = "*";
+
+/// Unit of work that queues hand out to nodes
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum Granularity {
+    /// Every test is queued on its own
+    #[default]
+    Test,
+    /// All tests of a binary are queued together, for binaries with expensive shared setup
+    Binary,
+}
+
+/// Test context queuing whole binaries, each as a test named [`BINARY_TEST_NAME`]. Time of a
+/// binary is its wall time, from the start of its first test to the end of its last one, as
+/// nextest runs its tests in parallel. It fails when any of them fails.
+pub struct PerBinary<C> {
+    inner: C,
+    tests: OnceLock<Vec<Test>>,
+}
+
+impl<C: TestContext> PerBinary<C> {
+    /// Groups tests of `inner` by binary
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            tests: OnceLock::new(),
+        }
+    }
+
+    // Tests are found once, also on nodes that only run binaries queued by another node
+    fn tests(&self) -> anyhow::Result<&[Test]> {
+        if let Some(tests) = self.tests.get() {
+            return Ok(tests);
+        }
+        let tests = self.inner.find_tests()?;
+        Ok(self.tests.get_or_init(|| tests))
+    }
+}
+
+impl<C: TestContext> TestContext for PerBinary<C> {
+    fn find_tests(&self) -> anyhow::Result<Vec<Test>> {
+        Ok(self
+            .tests()?
+            .iter()
+            .map(binary_of)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect())
+    }
+
+    fn run_tests(&self, tests: &[Test]) -> anyhow::Result<Vec<TestResult>> {
+        let binaries = tests.iter().map(binary_of).collect::<BTreeSet<_>>();
+        let tests_to_run = self
+            .tests()?
+            .iter()
+            .filter(|test| binaries.contains(&binary_of(test)))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut results = BTreeMap::<Test, Vec<TestResult>>::new();
+        for result in self.inner.run_tests(&tests_to_run)? {
+            results
+                .entry(binary_of(&result.test))
+                .or_default()
+                .push(result);
+        }
+        Ok(results
+            .into_iter()
+            .map(|(binary, results)| merge(binary, results))
+            .collect())
+    }
+}
+
+// Times of tests that don't tell when they finished (e.g. doctests, run one after another) add up
+fn merge(binary: Test, results: Vec<TestResult>) -> TestResult {
+    let started = results
+        .iter()
+        .map(|result| {
+            result
+                .finished_at?
+                .checked_sub(Duration::try_from_secs_f64(result.exec_time).ok()?)
+        })
+        .collect::<Option<Vec<_>>>()
+        .and_then(|started| started.into_iter().min());
+    let finished_at = results
+        .iter()
+        .map(|result| result.finished_at)
+        .collect::<Option<Vec<_>>>()
+        .and_then(|finished| finished.into_iter().max());
+    let exec_time = match (started, finished_at) {
+        (Some(started), Some(finished)) => finished.duration_since(started).as_secs_f64(),
+        _ => results.iter().map(|result| result.exec_time).sum(),
+    };
+
+    TestResult {
+        test: binary,
+        exec_time,
+        attempt: results
+            .iter()
+            .map(|result| result.attempt)
+            .max()
+            .unwrap_or(1),
+        outcome: results
+            .iter()
+            .map(|result| result.outcome)
+            .reduce(|merged, outcome| match (merged, outcome) {
+                (TestOutcome::Failed, _) | (_, TestOutcome::Failed) => TestOutcome::Failed,
+                (TestOutcome::TimedOut, _) | (_, TestOutcome::TimedOut) => TestOutcome::TimedOut,
+                (TestOutcome::Passed, TestOutcome::Passed) => TestOutcome::Passed,
+            })
+            .unwrap_or_default(),
+        finished_at,
+    }
+}
+
+fn binary_of(test: &Test) -> Test {
+    Test {
+        package_name: test.package_name.clone(),
+        binary_name: test.binary_name.clone(),
+        test_name: BINARY_TEST_NAME.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    // Runs every test, `b` fails and `c` passes on the second attempt
+    struct Binaries;
+
+    impl TestContext for Binaries {
+        fn find_tests(&self) -> anyhow::Result<Vec<Test>> {
+            Ok([("x", "a"), ("x", "b"), ("y", "c")]
+                .map(|(binary_name, test_name)| Test {
+                    package_name: "pn".into(),
+                    binary_name: binary_name.into(),
+                    test_name: test_name.into(),
+                })
+                .to_vec())
+        }
+
+        fn run_tests(&self, tests: &[Test]) -> anyhow::Result<Vec<TestResult>> {
+            Ok(tests
+                .iter()
+                .map(|test| TestResult {
+                    test: test.clone(),
+                    exec_time: 1.5,
+                    attempt: if test.test_name == "c" { 2 } else { 1 },
+                    outcome: if test.test_name == "b" {
+                        TestOutcome::Failed
+                    } else {
+                        TestOutcome::Passed
+                    },
+                    finished_at: None,
+                })
+                .collect())
+        }
+    }
+
+    #[test]
+    fn should_queue_and_run_whole_binaries() -> anyhow::Result<()> {
+        let context = PerBinary::new(Binaries);
+        let binaries = context.find_tests()?;
+        assert_eq!(
+            binaries
+                .iter()
+                .map(Test::to_knapsack_file)
+                .collect::<Vec<_>>(),
+            vec!["pn|x|*", "pn|y|*"]
+        );
+
+        let results = context.run_tests(&binaries)?;
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].test, binaries[0]);
+        assert_eq!(results[0].exec_time, 3.0);
+        assert_eq!(results[0].outcome, TestOutcome::Failed);
+        assert!(results[1].is_flaky());
+        Ok(())
+    }
+
+    #[test]
+    fn should_take_wall_time_of_tests_run_in_parallel() {
+        let started = Instant::now();
+        let result = |test_name: &str, exec_time: f64, finished: u64| TestResult {
+            test: Test {
+                package_name: "pn".into(),
+                binary_name: "x".into(),
+                test_name: test_name.into(),
+            },
+            exec_time,
+            attempt: 1,
+            outcome: TestOutcome::Passed,
+            finished_at: Some(started + Duration::from_secs(finished)),
+        };
+
+        // `b` starts a second after `a`, while `a` still runs
+        let merged = merge(
+            binary_of(&result("a", 0.0, 0).test),
+            vec![result("a", 2.0, 2), result("b", 2.0, 3)],
+        );
+
+        assert_eq!(merged.exec_time, 3.0);
+        assert_eq!(merged.finished_at, Some(started + Duration::from_secs(3)));
+    }
+}
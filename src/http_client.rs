@@ -0,0 +1,460 @@
+use crate::failure::{Classify, Failure};
+use anyhow::Context;
+use reqwest::StatusCode;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+use tracing::debug;
+
+/// Network settings of clients talking to Knapsack Pro or the coordinator. Clients are built
+/// once, on first use, and shared by clones of the configuration.
+#[derive(Clone, Debug, Default)]
+pub struct HttpConfig {
+    proxy: Option<String>,
+    ca_certificates: Vec<PathBuf>,
+    clients: Arc<Clients>,
+}
+
+#[derive(Debug, Default)]
+struct Clients {
+    blocking: OnceLock<reqwest::blocking::Client>,
+    #[cfg(feature = "async")]
+    r#async: OnceLock<reqwest::Client>,
+}
+
+impl HttpConfig {
+    /// Requests go through `proxy`, or the one from `HTTPS_PROXY`, `HTTP_PROXY` or `ALL_PROXY`
+    /// when not set, hosts listed in `NO_PROXY` are always reached directly. PEM files in
+    /// `ca_certificates` are trusted in addition to the built-in ones, e.g. of a proxy
+    /// intercepting HTTPS traffic.
+    pub fn new(proxy: Option<String>, ca_certificates: Vec<PathBuf>) -> Self {
+        Self {
+            proxy,
+            ca_certificates,
+            clients: Arc::default(),
+        }
+    }
+
+    /// Blocking client with this configuration, an invalid proxy or certificate is a
+    /// [`Failure::Configuration`]
+    pub fn blocking_client(&self) -> anyhow::Result<reqwest::blocking::Client> {
+        if let Some(client) = self.clients.blocking.get() {
+            return Ok(client.clone());
+        }
+        let mut builder = reqwest::blocking::Client::builder();
+        if let Some(proxy) = self.explicit_proxy().classify(Failure::Configuration)? {
+            builder = builder.proxy(proxy);
+        }
+        #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+        for certificate in self.certificates().classify(Failure::Configuration)? {
+            builder = builder.add_root_certificate(certificate);
+        }
+        #[cfg(not(any(feature = "native-tls", feature = "rustls-tls")))]
+        self.without_tls().classify(Failure::Configuration)?;
+        #[cfg(feature = "rustls-tls")]
+        {
+            builder = builder.use_rustls_tls();
+        }
+        let client = builder
+            .build()
+            .context("Failed to build HTTP client")
+            .classify(Failure::Configuration)?;
+        Ok(self.clients.blocking.get_or_init(|| client).clone())
+    }
+
+    /// Async client with this configuration, an invalid proxy or certificate is a
+    /// [`Failure::Configuration`]
+    #[cfg(feature = "async")]
+    pub fn async_client(&self) -> anyhow::Result<reqwest::Client> {
+        if let Some(client) = self.clients.r#async.get() {
+            return Ok(client.clone());
+        }
+        let mut builder = reqwest::Client::builder();
+        if let Some(proxy) = self.explicit_proxy().classify(Failure::Configuration)? {
+            builder = builder.proxy(proxy);
+        }
+        #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+        for certificate in self.certificates().classify(Failure::Configuration)? {
+            builder = builder.add_root_certificate(certificate);
+        }
+        #[cfg(not(any(feature = "native-tls", feature = "rustls-tls")))]
+        self.without_tls().classify(Failure::Configuration)?;
+        #[cfg(feature = "rustls-tls")]
+        {
+            builder = builder.use_rustls_tls();
+        }
+        let client = builder
+            .build()
+            .context("Failed to build HTTP client")
+            .classify(Failure::Configuration)?;
+        Ok(self.clients.r#async.get_or_init(|| client).clone())
+    }
+
+    /// Sends a request of [`Self::blocking_client`], logging its status and duration. A request
+    /// that wasn't answered is a [`Failure::Api`], see [`Self::request_error`].
+    pub fn execute(
+        &self,
+        request: reqwest::blocking::RequestBuilder,
+    ) -> anyhow::Result<reqwest::blocking::Response> {
+        let started = Instant::now();
+        let response = request.send().map_err(|e| self.request_error(e))?;
+        log_response(response.status(), started);
+        Ok(response)
+    }
+
+    /// Like [`Self::execute`], a response with an error status fails to `action`, see
+    /// [`status_failure`]
+    pub fn send(
+        &self,
+        request: reqwest::blocking::RequestBuilder,
+        action: &str,
+    ) -> anyhow::Result<reqwest::blocking::Response> {
+        let response = self.execute(request)?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response
+                .text()
+                .unwrap_or("Failed to get response".to_string());
+            return Err(status_error(status, &body, action));
+        }
+        Ok(response)
+    }
+
+    /// Async variant of [`Self::execute`], for requests of [`Self::async_client`]
+    #[cfg(feature = "async")]
+    pub async fn execute_async(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> anyhow::Result<reqwest::Response> {
+        let started = Instant::now();
+        let response = request.send().await.map_err(|e| self.request_error(e))?;
+        log_response(response.status(), started);
+        Ok(response)
+    }
+
+    /// Async variant of [`Self::send`], for requests of [`Self::async_client`]
+    #[cfg(feature = "async")]
+    pub async fn send_async(
+        &self,
+        request: reqwest::RequestBuilder,
+        action: &str,
+    ) -> anyhow::Result<reqwest::Response> {
+        let response = self.execute_async(request).await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response
+                .text()
+                .await
+                .unwrap_or("Failed to get response".to_string());
+            return Err(status_error(status, &body, action));
+        }
+        Ok(response)
+    }
+
+    /// Error of a request that wasn't answered, telling TLS, DNS and proxy failures apart.
+    /// It is a [`Failure::Api`], retrying may help.
+    pub fn request_error(&self, error: reqwest::Error) -> anyhow::Error {
+        let proxy = error.url().and_then(|url| self.proxy_in_use(url));
+        let url = error
+            .url()
+            .map(|url| url.as_str().to_string())
+            .unwrap_or_default();
+        let causes = error_chain(&error).to_lowercase();
+        let tls_failure = is_tls_failure(&causes);
+
+        // Proxy fails the connection to any host, also when the host of the proxy is unknown
+        let message = if let Some(proxy) = proxy.filter(|_| {
+            causes.contains("tunnel")
+                || causes.contains("proxy")
+                || (error.is_connect() && !tls_failure)
+        }) {
+            format!(
+                "Failed to reach [{url}] through proxy [{proxy}], check the proxy address or \
+                 add the host to NO_PROXY"
+            )
+        } else if causes.contains("dns error") || causes.contains("failed to lookup address") {
+            format!("Failed to resolve host of [{url}] (DNS)")
+        } else if tls_failure {
+            format!(
+                "TLS connection to [{url}] failed. If HTTPS traffic goes through an intercepting \
+                 proxy, trust its CA certificate with --ca-cert"
+            )
+        } else if error.is_timeout() {
+            format!("Request to [{url}] timed out")
+        } else if error.is_connect() {
+            format!("Failed to connect to [{url}]")
+        } else {
+            format!("Failed to execute request to [{url}]")
+        };
+
+        Failure::Api.error(anyhow::Error::new(error).context(message))
+    }
+
+    fn explicit_proxy(&self) -> anyhow::Result<Option<reqwest::Proxy>> {
+        self.proxy
+            .as_deref()
+            .map(|url| {
+                reqwest::Proxy::all(url)
+                    .map(|proxy| proxy.no_proxy(reqwest::NoProxy::from_env()))
+                    .with_context(|| format!("Invalid proxy URL [{url}]"))
+            })
+            .transpose()
+    }
+
+    // Proxy of requests to `url`, picked the way reqwest picks it, only used to describe failures
+    fn proxy_in_use(&self, url: &reqwest::Url) -> Option<String> {
+        let no_proxy = ["NO_PROXY", "no_proxy"]
+            .iter()
+            .find_map(|variable| std::env::var(variable).ok())
+            .unwrap_or_default();
+        if is_no_proxy_host(&no_proxy, url.host_str()?) {
+            return None;
+        }
+        let variables = if url.scheme() == "https" {
+            ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
+        } else {
+            ["HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"]
+        };
+        self.proxy.clone().or_else(|| {
+            variables
+                .iter()
+                .filter_map(|variable| std::env::var(variable).ok())
+                .find(|proxy| !proxy.is_empty())
+        })
+    }
+
+    #[cfg(not(any(feature = "native-tls", feature = "rustls-tls")))]
+    fn without_tls(&self) -> anyhow::Result<()> {
+        if let Some(path) = self.ca_certificates.first() {
+            anyhow::bail!(
+                "CA certificates [{}] can't be used, HTTPS is not supported by this build \
+                 (enable feature native-tls or rustls-tls)",
+                path.display()
+            );
+        }
+        Ok(())
+    }
+
+    #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+    fn certificates(&self) -> anyhow::Result<Vec<reqwest::Certificate>> {
+        let mut certificates = vec![];
+        for path in &self.ca_certificates {
+            let pem = std::fs::read(path)
+                .with_context(|| format!("Failed to read CA certificates [{}]", path.display()))?;
+            let bundle = reqwest::Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("Failed to parse CA certificates [{}]", path.display()))?;
+            if bundle.is_empty() {
+                anyhow::bail!("No CA certificates found in [{}]", path.display());
+            }
+            certificates.extend(bundle);
+        }
+        Ok(certificates)
+    }
+}
+
+/// Kind of failure of a request the server didn't accept, a rejected token won't pass on retry
+pub fn status_failure(status: StatusCode) -> Failure {
+    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        Failure::Configuration
+    } else {
+        Failure::Api
+    }
+}
+
+fn status_error(status: StatusCode, body: &str, action: &str) -> anyhow::Error {
+    status_failure(status).error(anyhow::anyhow!("Failed to {action}: [{status}] [{body}]"))
+}
+
+// Logged inside the span of the request, so the server and the path are known
+fn log_response(status: StatusCode, started: Instant) {
+    debug!(
+        status = status.as_u16(),
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        "Server responded"
+    );
+}
+
+// reqwest hides the cause (hyper, rustls, native-tls, io) in the source chain. Message of the
+// error itself is left out, it contains the URL, so a host like `ssl.example.com` isn't mistaken
+// for a TLS failure.
+fn error_chain(error: &reqwest::Error) -> String {
+    let mut causes = vec![];
+    let mut source = std::error::Error::source(error);
+    while let Some(error) = source {
+        causes.push(error.to_string());
+        source = error.source();
+    }
+    causes.join(": ")
+}
+
+// Rules of reqwest, see `reqwest::NoProxy::from_string`, which doesn't expose matching: `*`
+// matches all hosts, an IP address or network (`192.168.1.0/24`) matches addresses in it and
+// a domain (with an optional leading dot) matches itself and its subdomains
+fn is_no_proxy_host(no_proxy: &str, host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let address = host.parse::<IpAddr>().ok();
+    no_proxy
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            if entry == "*" {
+                return true;
+            }
+            match address {
+                Some(address) => is_in_network(address, entry),
+                None => {
+                    let domain = entry.strip_prefix('.').unwrap_or(entry);
+                    host == domain
+                        || host
+                            .strip_suffix(domain)
+                            .is_some_and(|subdomain| subdomain.ends_with('.'))
+                }
+            }
+        })
+}
+
+fn is_in_network(address: IpAddr, network: &str) -> bool {
+    let (network, prefix) = match network.split_once('/') {
+        Some((network, prefix)) => (network, prefix.parse::<u32>().ok()),
+        None => (network, None),
+    };
+    let bits = |address: IpAddr| match address {
+        IpAddr::V4(address) => (u128::from(u32::from(address)), 32),
+        IpAddr::V6(address) => (u128::from(address), 128),
+    };
+    let Ok(network) = network.parse::<IpAddr>() else {
+        return false;
+    };
+    let ((address, width), (network, network_width)) = (bits(address), bits(network));
+    let prefix = prefix.unwrap_or(width);
+    width == network_width
+        && prefix <= width
+        && (address ^ network).checked_shr(width - prefix).unwrap_or(0) == 0
+}
+
+// native-tls reports OpenSSL/Schannel/Security.framework errors, rustls its own (e.g. "received
+// corrupt message" when the server doesn't speak TLS)
+fn is_tls_failure(causes: &str) -> bool {
+    ["certificate", "tls", "ssl", "handshake", "corrupt message"]
+        .iter()
+        .any(|keyword| causes.contains(keyword))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure(config: &HttpConfig, url: &str) -> String {
+        let error = config
+            .blocking_client()
+            .unwrap()
+            .get(url)
+            .send()
+            .unwrap_err();
+        config.request_error(error).to_string()
+    }
+
+    #[test]
+    fn should_describe_request_failures() {
+        let direct = HttpConfig::default();
+        assert_eq!(
+            failure(&direct, "http://nextest-knapsack.invalid/"),
+            "Failed to resolve host of [http://nextest-knapsack.invalid/] (DNS)"
+        );
+
+        let proxied = HttpConfig::new(Some("http://127.0.0.1:1".into()), vec![]);
+        assert!(failure(&proxied, "http://nextest-knapsack.invalid/").starts_with(
+            "Failed to reach [http://nextest-knapsack.invalid/] through proxy [http://127.0.0.1:1]"
+        ));
+    }
+
+    #[test]
+    #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+    fn should_describe_tls_failure() {
+        use std::io::Write;
+        use std::net::TcpListener;
+
+        // Server that doesn't speak TLS
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = (&stream).write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+            }
+        });
+        assert!(
+            failure(&HttpConfig::default(), &format!("https://{address}/"))
+                .starts_with(&format!("TLS connection to [https://{address}/] failed"))
+        );
+    }
+
+    #[test]
+    fn should_not_take_host_for_tls_failure() {
+        // Host that can't be resolved, its name mentions TLS
+        assert_eq!(
+            failure(
+                &HttpConfig::default(),
+                "http://tls.nextest-knapsack.invalid/"
+            ),
+            "Failed to resolve host of [http://tls.nextest-knapsack.invalid/] (DNS)"
+        );
+    }
+
+    #[test]
+    fn should_match_no_proxy_hosts() {
+        let no_proxy = "example.com, .internal,192.168.1.0/24, ::1";
+        assert!(is_no_proxy_host(no_proxy, "example.com"));
+        assert!(is_no_proxy_host(no_proxy, "api.example.com"));
+        assert!(!is_no_proxy_host(no_proxy, "notexample.com"));
+        assert!(is_no_proxy_host(no_proxy, "internal"));
+        assert!(is_no_proxy_host(no_proxy, "knapsack.internal"));
+        assert!(is_no_proxy_host(no_proxy, "192.168.1.42"));
+        assert!(!is_no_proxy_host(no_proxy, "192.168.2.42"));
+        assert!(is_no_proxy_host(no_proxy, "[::1]"));
+        assert!(!is_no_proxy_host(no_proxy, "127.0.0.1"));
+        assert!(is_no_proxy_host("*", "knapsackpro.com"));
+        assert!(!is_no_proxy_host("", "knapsackpro.com"));
+    }
+
+    #[test]
+    fn should_share_client_between_clones() -> anyhow::Result<()> {
+        let config = HttpConfig::default();
+        let clone = config.clone();
+
+        config.blocking_client()?;
+
+        assert!(clone.clients.blocking.get().is_some());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(not(any(feature = "native-tls", feature = "rustls-tls")))]
+    fn should_reject_certificates_without_tls() {
+        let config = HttpConfig::new(None, vec![PathBuf::from("ca.pem")]);
+
+        let error = config.blocking_client().unwrap_err();
+
+        assert_eq!(Failure::of(&error), Some(Failure::Configuration));
+    }
+
+    #[test]
+    #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+    fn should_reject_file_without_certificates() -> anyhow::Result<()> {
+        use std::fs;
+
+        let path = std::env::temp_dir().join(format!("{}.pem", uuid::Uuid::new_v4()));
+        fs::write(&path, "not a certificate")?;
+        let config = HttpConfig::new(None, vec![path.clone()]);
+
+        let error = config.blocking_client().unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            format!("No CA certificates found in [{}]", path.display())
+        );
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
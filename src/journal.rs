@@ -0,0 +1,403 @@
+use crate::ci_providers::ci_provider_base::CiProvider;
+use crate::ci_providers::ci_provider_wrapper::CiProviderWrapper;
+use crate::failure::{Classify, Failure};
+use crate::models::{Test, TestOutcome, TestResult};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Journal is a JSON Lines file. First line describes the node, every following line is either
+// a finished batch or a marker that all results above were uploaded.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JournalEntry {
+    Node(JournalNode),
+    Batch {
+        test_files: Vec<JournalTestFile>,
+        // Seconds spent running the batch, missing in journals of older versions
+        #[serde(default)]
+        wall_time: f64,
+    },
+    Uploaded,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JournalTestFile {
+    path: String,
+    time_execution: f64,
+    // Only recorded for tests that were retried
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    attempt: Option<u32>,
+    // Only recorded for tests that didn't pass
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    outcome: Option<TestOutcome>,
+}
+
+/// Node whose results are recorded in the journal
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JournalNode {
+    /// Commit the build ran on
+    pub commit_hash: String,
+    /// Branch the build ran on
+    pub branch: String,
+    /// Number of nodes of the build
+    pub node_total: usize,
+    /// Index of the node
+    pub node_index: usize,
+    /// Identifier shared by all nodes of the build
+    pub node_build_id: String,
+    /// Whether a retried build gets the same split
+    pub fixed_queue_split: bool,
+    /// Test suite the results belong to, none when the node runs a single suite
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suite: Option<String>,
+}
+
+impl JournalNode {
+    /// Node this process runs on
+    pub fn from_ci_provider(ci_provider_wrapper: &CiProviderWrapper) -> anyhow::Result<Self> {
+        Ok(Self {
+            commit_hash: ci_provider_wrapper
+                .get_commit_hash()
+                .context("Failed to get commit hash")
+                .classify(Failure::Configuration)?,
+            branch: ci_provider_wrapper
+                .get_branch()
+                .context("Failed to get branch")
+                .classify(Failure::Configuration)?,
+            node_total: ci_provider_wrapper
+                .get_ci_node_total()
+                .context("Failed to get node total")
+                .classify(Failure::Configuration)?,
+            node_index: ci_provider_wrapper
+                .get_ci_node_index()
+                .context("Failed to get node index")
+                .classify(Failure::Configuration)?,
+            node_build_id: ci_provider_wrapper.get_ci_node_build_id(),
+            fixed_queue_split: ci_provider_wrapper.is_fixed_queue_split(),
+            suite: None,
+        })
+    }
+}
+
+// Allows uploading a journal without the CI environment it was recorded in
+impl CiProvider for JournalNode {
+    fn get_ci_node_total(&self) -> Option<usize> {
+        Some(self.node_total)
+    }
+
+    fn get_ci_node_index(&self) -> Option<usize> {
+        Some(self.node_index)
+    }
+
+    fn get_ci_node_build_id(&self) -> Option<String> {
+        Some(self.node_build_id.clone())
+    }
+
+    fn get_commit_hash(&self) -> Option<String> {
+        Some(self.commit_hash.clone())
+    }
+
+    fn is_fixed_queue_split(&self) -> bool {
+        self.fixed_queue_split
+    }
+
+    fn get_branch(&self) -> Option<String> {
+        Some(self.branch.clone())
+    }
+}
+
+/// Append-only record of results of a node, survives crashes between batches
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+/// Content of a journal
+#[derive(Debug)]
+pub struct RecordedJournal {
+    /// Node the journal belongs to
+    pub node: JournalNode,
+    /// Results of all batches, latest result for tests recorded more than once
+    pub results: Vec<TestResult>,
+    /// Number of recorded batches
+    pub batches: usize,
+    /// Sum of wall time of all batches
+    pub busy_time: f64,
+    /// Whether all results were uploaded
+    pub uploaded: bool,
+}
+
+impl Journal {
+    /// Default path of the journal of given node in `directory`
+    pub fn path_for(directory: &Path, node: &JournalNode) -> PathBuf {
+        directory.join(format!("journal-{}.jsonl", node_file_key(node)))
+    }
+
+    /// Starts a new journal, previous content for the same node and build is discarded
+    pub fn create(path: &Path, node: &JournalNode) -> anyhow::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory [{}]", parent.display()))?;
+        }
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create journal [{}]", path.display()))?;
+        let mut journal = Self {
+            path: path.to_path_buf(),
+            file,
+        };
+        journal.append(&JournalEntry::Node(node.clone()))?;
+        Ok(journal)
+    }
+
+    /// Opens existing journal for appending
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open journal [{}]", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// Path of the journal file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends results of a finished batch
+    pub fn record_batch(
+        &mut self,
+        results: &[TestResult],
+        wall_time: Duration,
+    ) -> anyhow::Result<()> {
+        let test_files = results
+            .iter()
+            .map(|result| JournalTestFile {
+                path: result.test.to_knapsack_file(),
+                time_execution: result.exec_time,
+                attempt: (result.attempt > 1).then_some(result.attempt),
+                outcome: (result.outcome != TestOutcome::Passed).then_some(result.outcome),
+            })
+            .collect();
+        self.append(&JournalEntry::Batch {
+            test_files,
+            wall_time: wall_time.as_secs_f64(),
+        })
+    }
+
+    /// Records that all results above were uploaded
+    pub fn mark_uploaded(&mut self) -> anyhow::Result<()> {
+        self.append(&JournalEntry::Uploaded)
+    }
+
+    fn append(&mut self, entry: &JournalEntry) -> anyhow::Result<()> {
+        let line = serde_json::to_string(entry).context("Failed to serialize journal entry")?;
+        writeln!(self.file, "{}", line)
+            .and_then(|_| self.file.sync_data())
+            .with_context(|| format!("Failed to write journal [{}]", self.path.display()))
+    }
+
+    /// Reads a journal, tolerates a truncated last line
+    pub fn read(path: &Path) -> anyhow::Result<RecordedJournal> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open journal [{}]", path.display()))?;
+
+        let mut node = None;
+        let mut uploaded = false;
+        let mut batches = 0;
+        let mut busy_time = 0.0;
+        // Test reported more than once keeps its latest time, so uploading is idempotent
+        let mut results = BTreeMap::new();
+
+        for (index, line) in BufReader::new(file).lines().enumerate() {
+            let line =
+                line.with_context(|| format!("Failed to read journal [{}]", path.display()))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: JournalEntry = match serde_json::from_str(&line) {
+                Ok(entry) => entry,
+                // Process may have been killed while writing the last line
+                Err(e) if e.is_eof() => break,
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!(
+                            "Invalid entry in journal [{}] line {}",
+                            path.display(),
+                            index + 1
+                        )
+                    })
+                }
+            };
+            match entry {
+                JournalEntry::Node(n) => node = Some(n),
+                JournalEntry::Batch {
+                    test_files,
+                    wall_time,
+                } => {
+                    batches += 1;
+                    busy_time += wall_time;
+                    for file in test_files {
+                        let test = Test::from_knapsack_file(&file.path).with_context(|| {
+                            format!("Failed to parse test file: {}", &file.path)
+                        })?;
+                        results.insert(
+                            test,
+                            (
+                                file.time_execution,
+                                file.attempt.unwrap_or(1),
+                                file.outcome.unwrap_or_default(),
+                            ),
+                        );
+                    }
+                    uploaded = false;
+                }
+                JournalEntry::Uploaded => uploaded = true,
+            }
+        }
+
+        let node = node
+            .with_context(|| format!("Journal [{}] does not describe CI node", path.display()))?;
+
+        Ok(RecordedJournal {
+            node,
+            results: results
+                .into_iter()
+                .map(|(test, (exec_time, attempt, outcome))| TestResult {
+                    test,
+                    exec_time,
+                    attempt,
+                    outcome,
+                    finished_at: None,
+                })
+                .collect(),
+            batches,
+            busy_time,
+            uploaded,
+        })
+    }
+}
+
+// Part of names of files kept per node, `<build id>-<node index>[-<suite>]`
+pub(crate) fn node_file_key(node: &JournalNode) -> String {
+    let suite = node
+        .suite
+        .as_deref()
+        .map(|suite| format!("-{}", path_safe(suite)))
+        .unwrap_or_default();
+    format!(
+        "{}-{}{suite}",
+        path_safe(&node.node_build_id),
+        node.node_index
+    )
+}
+
+// Escapes bytes that are not safe in file names (and `_` itself) as `_XX`, so different build
+// ids, e.g. `a/b`, `a.b` and `a_b`, never share a file
+pub(crate) fn path_safe(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| {
+            if byte.is_ascii_alphanumeric() || byte == b'-' {
+                char::from(byte).to_string()
+            } else {
+                format!("_{byte:02X}")
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node() -> JournalNode {
+        JournalNode {
+            commit_hash: "commit_hash".into(),
+            branch: "branch".into(),
+            node_total: 4,
+            node_index: 1,
+            node_build_id: "build/id".into(),
+            fixed_queue_split: true,
+            suite: None,
+        }
+    }
+
+    fn result(test_name: &str, exec_time: f64) -> TestResult {
+        TestResult {
+            test: Test {
+                package_name: "pn".into(),
+                binary_name: "bn".into(),
+                test_name: test_name.into(),
+            },
+            exec_time,
+            attempt: 1,
+            outcome: TestOutcome::Passed,
+            finished_at: None,
+        }
+    }
+
+    #[test]
+    fn should_keep_build_ids_apart_in_file_names() {
+        let escaped = ["a/b", "a.b", "a_b", "a_2Fb"].map(path_safe);
+        assert_eq!(escaped, ["a_2Fb", "a_2Eb", "a_5Fb", "a_5F2Fb"]);
+        assert_eq!(path_safe("3f2c-9a"), "3f2c-9a");
+    }
+
+    #[test]
+    fn should_read_recorded_batches() -> anyhow::Result<()> {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let path = Journal::path_for(&directory, &node());
+        assert_eq!(path, directory.join("journal-build_2Fid-1.jsonl"));
+        let suite = JournalNode {
+            suite: Some("unit".into()),
+            ..node()
+        };
+        assert_eq!(
+            Journal::path_for(&directory, &suite),
+            directory.join("journal-build_2Fid-1-unit.jsonl")
+        );
+
+        let mut journal = Journal::create(&path, &node())?;
+        journal.record_batch(
+            &[result("a", 1.0), result("b", 2.0)],
+            Duration::from_secs(2),
+        )?;
+        let flaky = || TestResult {
+            attempt: 2,
+            ..result("c", 1.0)
+        };
+        let timed_out = || TestResult {
+            outcome: TestOutcome::TimedOut,
+            ..result("d", 60.0)
+        };
+        journal.record_batch(
+            &[result("a", 3.0), flaky(), timed_out()],
+            Duration::from_secs(63),
+        )?;
+
+        let recorded = Journal::read(&path)?;
+        assert_eq!(recorded.node, node());
+        assert_eq!(
+            recorded.results,
+            vec![result("a", 3.0), result("b", 2.0), flaky(), timed_out()]
+        );
+        assert_eq!(recorded.batches, 2);
+        assert_eq!(recorded.busy_time, 65.0);
+        assert!(!recorded.uploaded);
+
+        journal.mark_uploaded()?;
+        assert!(Journal::read(&path)?.uploaded);
+
+        fs::remove_dir_all(directory)?;
+        Ok(())
+    }
+}
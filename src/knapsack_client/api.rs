@@ -0,0 +1,302 @@
+//! Requests and responses of the Knapsack Pro API, shared by the blocking and the async client
+
+use crate::ci_providers::ci_provider_wrapper::CiProviderWrapper;
+use crate::failure::{Classify, Failure};
+use crate::http_client::status_failure;
+use crate::knapsack_client::token::TestSuiteToken;
+use crate::models::{Test, TestResult};
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+/// Queue mode: initializes the queue or takes the next batch from it
+pub const QUEUE_PATH: &str = "/v1/queues/queue";
+/// Regular mode: the whole split for the node
+pub const SUBSET_PATH: &str = "/v1/build_distributions/subset";
+/// Uploads times of tests executed by the node
+pub const BUILD_SUBSETS_PATH: &str = "/v1/build_subsets";
+/// Split of the last build of the commit, read-only so it is used to check the token
+pub const LAST_BUILD_DISTRIBUTION_PATH: &str = "/v1/build_distributions/last";
+
+// Returned when the queue was not initialized yet and the client has to send all tests
+const ATTEMPT_CONNECT_TO_QUEUE_FAILED: &str = "ATTEMPT_CONNECT_TO_QUEUE_FAILED";
+
+/// Headers sent with every request, the token is marked as sensitive so it isn't printed
+pub fn headers(token: &TestSuiteToken) -> Result<HeaderMap> {
+    let mut token = HeaderValue::from_str(token.expose())
+        .map_err(|_| anyhow::anyhow!("Test suite token is not a valid header value"))?;
+    token.set_sensitive(true);
+
+    let mut headers = HeaderMap::new();
+    headers.insert("KNAPSACK-PRO-TEST-SUITE-TOKEN", token);
+    headers.insert(
+        "KNAPSACK-PRO-CLIENT-NAME",
+        HeaderValue::from_static("cargo-nextest-knapsack"),
+    );
+    headers.insert(
+        "KNAPSACK-PRO-CLIENT-VERSION",
+        HeaderValue::from_static(env!("CARGO_PKG_VERSION")),
+    );
+    Ok(headers)
+}
+
+/// Fails with a clear message when the API rejected the token of [`LAST_BUILD_DISTRIBUTION_PATH`]
+/// request. Other failures are left to requests that need the response.
+pub fn check_token_status(status: StatusCode) -> Result<()> {
+    if status_failure(status) == Failure::Configuration {
+        return Err(Failure::Configuration.error(anyhow::anyhow!(
+            "Invalid test suite token, Knapsack Pro rejected it with [{status}]. \
+             Check KNAPSACK_PRO_TEST_SUITE_TOKEN or --token-file"
+        )));
+    }
+    Ok(())
+}
+
+/// Test file as sent to and returned by Knapsack Pro
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TestFile {
+    /// Path of the test, see [`Test::to_knapsack_file`]
+    pub path: String,
+    /// Execution time in seconds, sent only with results
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_execution: Option<f64>,
+}
+
+impl TestFile {
+    /// Test sent without time, e.g. when the queue is initialized
+    pub fn from_test(test: &Test) -> Self {
+        Self {
+            path: test.to_knapsack_file(),
+            time_execution: None,
+        }
+    }
+
+    /// Time of an executed test
+    pub fn from_result(result: &TestResult) -> Self {
+        Self {
+            path: result.test.to_knapsack_file(),
+            time_execution: Some(result.exec_time),
+        }
+    }
+}
+
+/// Body of [`QUEUE_PATH`] request
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QueueRequest {
+    /// Whether the queue may be created by this request
+    pub can_initialize_queue: bool,
+    /// Whether the queue is only expected to exist, tests are sent when it doesn't
+    pub attempt_connect_to_queue: bool,
+    /// Whether a retried build gets the same split
+    pub fixed_queue_split: bool,
+    /// Commit the build runs on
+    pub commit_hash: String,
+    /// Branch the build runs on
+    pub branch: String,
+    /// Number of nodes of the build
+    pub node_total: usize,
+    /// Index of the node
+    pub node_index: usize,
+    /// Identifier shared by all nodes of the build
+    pub node_build_id: String,
+    /// All tests of the build, sent only when the queue is initialized
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub test_files: Option<Vec<TestFile>>,
+}
+
+impl QueueRequest {
+    /// First request of the node, connects to the queue if another node already created it
+    pub fn connect(ci_provider_wrapper: &CiProviderWrapper) -> Result<Self> {
+        Self::new(ci_provider_wrapper, true, true, None)
+    }
+
+    /// Creates the queue from all tests of the build
+    pub fn initialize(ci_provider_wrapper: &CiProviderWrapper, tests: &[Test]) -> Result<Self> {
+        let test_files = tests.iter().map(TestFile::from_test).collect();
+        Self::new(ci_provider_wrapper, true, false, Some(test_files))
+    }
+
+    /// Takes the next batch from the queue
+    pub fn next_batch(ci_provider_wrapper: &CiProviderWrapper) -> Result<Self> {
+        Self::new(ci_provider_wrapper, false, false, None)
+    }
+
+    fn new(
+        ci_provider_wrapper: &CiProviderWrapper,
+        can_initialize_queue: bool,
+        attempt_connect_to_queue: bool,
+        test_files: Option<Vec<TestFile>>,
+    ) -> Result<Self> {
+        let node = BuildNode::from_ci_provider(ci_provider_wrapper)?;
+        Ok(Self {
+            can_initialize_queue,
+            attempt_connect_to_queue,
+            fixed_queue_split: ci_provider_wrapper.is_fixed_queue_split(),
+            commit_hash: node.commit_hash,
+            branch: node.branch,
+            node_total: node.node_total,
+            node_index: node.node_index,
+            node_build_id: ci_provider_wrapper.get_ci_node_build_id(),
+            test_files,
+        })
+    }
+}
+
+/// Body of [`SUBSET_PATH`] request
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SubsetRequest {
+    /// Whether a retried build gets the same split
+    pub fixed_test_suite_split: bool,
+    /// Whether the split may be read from the cache
+    pub cache_read_attempt: bool,
+    /// Commit the build runs on
+    pub commit_hash: String,
+    /// Branch the build runs on
+    pub branch: String,
+    /// Number of nodes of the build
+    pub node_total: usize,
+    /// Index of the node
+    pub node_index: usize,
+    /// Identifier shared by all nodes of the build
+    pub ci_build_id: String,
+    /// All tests of the build
+    pub test_files: Vec<TestFile>,
+}
+
+impl SubsetRequest {
+    /// Asks for this node's part of given tests
+    pub fn new(ci_provider_wrapper: &CiProviderWrapper, tests: &[Test]) -> Result<Self> {
+        let node = BuildNode::from_ci_provider(ci_provider_wrapper)?;
+        Ok(Self {
+            fixed_test_suite_split: true,
+            cache_read_attempt: false,
+            commit_hash: node.commit_hash,
+            branch: node.branch,
+            node_total: node.node_total,
+            node_index: node.node_index,
+            ci_build_id: ci_provider_wrapper.get_ci_node_build_id(),
+            test_files: tests.iter().map(TestFile::from_test).collect(),
+        })
+    }
+}
+
+/// Body of [`BUILD_SUBSETS_PATH`] request
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BuildSubsetRequest {
+    /// Commit the build ran on
+    pub commit_hash: String,
+    /// Branch the build ran on
+    pub branch: String,
+    /// Number of nodes of the build
+    pub node_total: usize,
+    /// Index of the node
+    pub node_index: usize,
+    /// Tests executed by the node, with their times
+    pub test_files: Vec<TestFile>,
+}
+
+impl BuildSubsetRequest {
+    /// Uploads given results
+    pub fn new(ci_provider_wrapper: &CiProviderWrapper, results: &[TestResult]) -> Result<Self> {
+        let node = BuildNode::from_ci_provider(ci_provider_wrapper)?;
+        Ok(Self {
+            commit_hash: node.commit_hash,
+            branch: node.branch,
+            node_total: node.node_total,
+            node_index: node.node_index,
+            test_files: results.iter().map(TestFile::from_result).collect(),
+        })
+    }
+}
+
+/// Query of [`LAST_BUILD_DISTRIBUTION_PATH`] request
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LastBuildDistributionQuery {
+    /// Commit the build runs on
+    pub commit_hash: String,
+    /// Branch the build runs on
+    pub branch: String,
+    /// Number of nodes of the build
+    pub node_total: usize,
+    /// Index of the node
+    pub node_index: usize,
+}
+
+impl LastBuildDistributionQuery {
+    /// Asks for the last split of this node
+    pub fn new(ci_provider_wrapper: &CiProviderWrapper) -> Result<Self> {
+        let node = BuildNode::from_ci_provider(ci_provider_wrapper)?;
+        Ok(Self {
+            commit_hash: node.commit_hash,
+            branch: node.branch,
+            node_total: node.node_total,
+            node_index: node.node_index,
+        })
+    }
+}
+
+/// Response of [`QUEUE_PATH`] and [`SUBSET_PATH`] requests
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct TestFilesResponse {
+    /// Set when the request couldn't be fulfilled
+    #[serde(default)]
+    pub code: Option<String>,
+    /// Tests for the node
+    #[serde(default)]
+    pub test_files: Option<Vec<TestFile>>,
+}
+
+impl TestFilesResponse {
+    /// Whether the queue doesn't exist yet and has to be initialized with all tests
+    pub fn is_queue_missing(&self) -> bool {
+        self.code.as_deref() == Some(ATTEMPT_CONNECT_TO_QUEUE_FAILED)
+    }
+
+    /// Tests for the node
+    pub fn into_tests(self) -> Result<Vec<Test>> {
+        let test_files = self
+            .test_files
+            .context("Failed to parse response, it has no test files")
+            .classify(Failure::Api)?;
+
+        let mut tests = vec![];
+        for file in test_files {
+            tests.push(
+                Test::from_knapsack_file(&file.path)
+                    .with_context(|| format!("Failed to parse test file: {}", &file.path))?,
+            );
+        }
+        Ok(tests)
+    }
+}
+
+struct BuildNode {
+    commit_hash: String,
+    branch: String,
+    node_total: usize,
+    node_index: usize,
+}
+
+impl BuildNode {
+    fn from_ci_provider(ci_provider_wrapper: &CiProviderWrapper) -> Result<Self> {
+        Ok(Self {
+            commit_hash: ci_provider_wrapper
+                .get_commit_hash()
+                .context("Failed to get commit hash")
+                .classify(Failure::Configuration)?,
+            branch: ci_provider_wrapper
+                .get_branch()
+                .context("Failed to get branch")
+                .classify(Failure::Configuration)?,
+            node_total: ci_provider_wrapper
+                .get_ci_node_total()
+                .context("Failed to get node total")
+                .classify(Failure::Configuration)?,
+            node_index: ci_provider_wrapper
+                .get_ci_node_index()
+                .context("Failed to get node index")
+                .classify(Failure::Configuration)?,
+        })
+    }
+}
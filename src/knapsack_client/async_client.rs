@@ -0,0 +1,238 @@
+//! Async variant of [`KnapsackClient`](super::KnapsackClient), for use inside async runtimes
+//! where `reqwest::blocking` panics
+
+use crate::ci_providers::ci_provider_wrapper::CiProviderWrapper;
+use crate::failure::{Classify, Failure};
+use crate::http_client::HttpConfig;
+use crate::knapsack_client::api::{
+    self, BuildSubsetRequest, LastBuildDistributionQuery, TestFilesResponse, BUILD_SUBSETS_PATH,
+    LAST_BUILD_DISTRIBUTION_PATH,
+};
+use crate::knapsack_client::token::TestSuiteToken;
+use crate::knapsack_client::{KnapsackMode, TestsProtocol, TestsStep};
+use crate::models::{Test, TestResult};
+use crate::queue_backend::AsyncQueueBackend;
+use crate::test_context::TestContext;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::{debug_span, Instrument};
+
+/// Async client of the Knapsack Pro API, or of a server compatible with it
+///
+/// Test discovery ([`TestContext::find_tests`]) runs cargo and nextest, it is run on the blocking
+/// thread pool of the tokio runtime the client is polled in.
+pub struct AsyncKnapsackClient {
+    protocol: TestsProtocol,
+    endpoint: String,
+    token: TestSuiteToken,
+    test_context: Option<Arc<dyn TestContext + Send + Sync>>,
+    ci_provider_wrapper: CiProviderWrapper,
+    http: HttpConfig,
+}
+
+impl AsyncKnapsackClient {
+    /// Client of `endpoint` authenticated with test suite `token`, in queue mode
+    pub fn new(
+        endpoint: String,
+        token: TestSuiteToken,
+        test_context: Arc<dyn TestContext + Send + Sync>,
+        ci_provider_wrapper: CiProviderWrapper,
+    ) -> AsyncKnapsackClient {
+        AsyncKnapsackClient {
+            protocol: TestsProtocol::new(KnapsackMode::Queue),
+            endpoint,
+            token,
+            test_context: Some(test_context),
+            ci_provider_wrapper,
+            http: HttpConfig::default(),
+        }
+    }
+
+    /// Client that can only upload results, e.g. from a journal recorded earlier
+    pub fn without_test_context(
+        endpoint: String,
+        token: TestSuiteToken,
+        ci_provider_wrapper: CiProviderWrapper,
+    ) -> AsyncKnapsackClient {
+        AsyncKnapsackClient {
+            protocol: TestsProtocol::new(KnapsackMode::Queue),
+            endpoint,
+            token,
+            test_context: None,
+            ci_provider_wrapper,
+            http: HttpConfig::default(),
+        }
+    }
+
+    /// Sets how tests are fetched
+    pub fn with_mode(self, mode: KnapsackMode) -> Self {
+        Self {
+            protocol: TestsProtocol::new(mode),
+            ..self
+        }
+    }
+
+    /// Sets proxy and CA certificates used to reach the API
+    pub fn with_http_config(self, http: HttpConfig) -> Self {
+        Self { http, ..self }
+    }
+
+    /// Checks the token with a read-only request, fails with a clear message when it is rejected
+    pub async fn validate_token(&self) -> Result<()> {
+        let span = debug_span!(
+            "api_request",
+            method = "GET",
+            path = LAST_BUILD_DISTRIBUTION_PATH
+        );
+        let query = LastBuildDistributionQuery::new(&self.ci_provider_wrapper)?;
+        let request = self
+            .http
+            .async_client()?
+            .get(format!("{}{}", self.endpoint, LAST_BUILD_DISTRIBUTION_PATH))
+            .headers(api::headers(&self.token)?)
+            .query(&query);
+        let response = self.http.execute_async(request).instrument(span).await?;
+        api::check_token_status(response.status())
+    }
+
+    // Discovery blocks on cargo and nextest, so it must not stall a worker thread of the runtime
+    async fn find_tests(&self) -> Result<Vec<Test>> {
+        let test_context = self
+            .test_context
+            .clone()
+            .context("Cannot send tests without test context")?;
+        tokio::task::spawn_blocking(move || test_context.find_tests())
+            .await
+            .context("Test discovery was cancelled or panicked")?
+            .context("Failed to find tests")
+    }
+
+    async fn post(
+        &self,
+        path: &str,
+        body: &(impl Serialize + Sync),
+        action: &str,
+    ) -> Result<reqwest::Response> {
+        let span = debug_span!("api_request", method = "POST", path);
+        let request = self
+            .http
+            .async_client()?
+            .post(format!("{}{}", self.endpoint, path))
+            .headers(api::headers(&self.token)?)
+            .json(body);
+        self.http.send_async(request, action).instrument(span).await
+    }
+
+    async fn get_test_files(
+        &self,
+        path: &str,
+        body: &(impl Serialize + Sync),
+        action: &str,
+    ) -> Result<TestFilesResponse> {
+        self.post(path, body, action)
+            .await?
+            .json::<TestFilesResponse>()
+            .await
+            .context("Failed to parse response")
+            .classify(Failure::Api)
+    }
+}
+
+impl AsyncQueueBackend for AsyncKnapsackClient {
+    async fn get_tests(&mut self) -> Result<Vec<Test>> {
+        let mut step = self.protocol.start(&self.ci_provider_wrapper)?;
+        loop {
+            step = match step {
+                TestsStep::FindTests => {
+                    let tests = self.find_tests().await?;
+                    self.protocol.found(&self.ci_provider_wrapper, &tests)?
+                }
+                TestsStep::Send(request) => {
+                    let response = self
+                        .get_test_files(request.path, &request.body, request.action)
+                        .await?;
+                    self.protocol.received(response)?
+                }
+                TestsStep::Done(tests) => return Ok(tests),
+            };
+        }
+    }
+
+    async fn upload_test_results(&self, test_results: &[TestResult]) -> Result<()> {
+        let request = BuildSubsetRequest::new(&self.ci_provider_wrapper, test_results)?;
+        self.post(BUILD_SUBSETS_PATH, &request, "upload test results")
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TestOutcome;
+    use crate::test_utils::{FixedTests, TestNode};
+    use httpmock::prelude::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn should_initialize_queue_and_upload_results() -> Result<()> {
+        let server = MockServer::start_async().await;
+
+        let connect = server
+            .mock_async(|when, then| {
+                when.path("/v1/queues/queue")
+                    .header("KNAPSACK-PRO-TEST-SUITE-TOKEN", "test_api_key")
+                    .json_body_partial(r#"{ "attempt_connect_to_queue": true }"#);
+                then.status(200)
+                    .json_body(json!({ "code": "ATTEMPT_CONNECT_TO_QUEUE_FAILED" }));
+            })
+            .await;
+        let initialize = server
+            .mock_async(|when, then| {
+                when.path("/v1/queues/queue")
+                    .json_body_partial(r#"{ "test_files": [{ "path": "pn|bn|a" }] }"#);
+                then.status(200)
+                    .json_body(json!({ "test_files": [{ "path": "pn|bn|a" }] }));
+            })
+            .await;
+        let upload = server
+            .mock_async(|when, then| {
+                when.path("/v1/build_subsets").json_body(json!({
+                    "commit_hash": "commit_hash",
+                    "branch": "branch",
+                    "node_total": 2,
+                    "node_index": 0,
+                    "test_files": [{ "path": "pn|bn|a", "time_execution": 1.5 }]
+                }));
+                then.status(200).json_body(json!({}));
+            })
+            .await;
+
+        let tests = Arc::new(FixedTests::new(&["a"]));
+        let mut client = AsyncKnapsackClient::new(
+            server.base_url(),
+            TestSuiteToken::new("test_api_key")?,
+            tests.clone(),
+            CiProviderWrapper::new(Box::new(TestNode::new(0, 2))),
+        );
+
+        let batch = client.get_tests().await?;
+        client
+            .upload_test_results(&[TestResult {
+                test: batch[0].clone(),
+                exec_time: 1.5,
+                attempt: 1,
+                outcome: TestOutcome::Passed,
+                finished_at: None,
+            }])
+            .await?;
+
+        connect.assert_async().await;
+        initialize.assert_async().await;
+        upload.assert_async().await;
+        assert_eq!(batch, tests.find_tests()?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,597 @@
+use crate::ci_providers::ci_provider_wrapper::CiProviderWrapper;
+use crate::failure::{Classify, Failure};
+use crate::http_client::HttpConfig;
+use crate::knapsack_client::api::{
+    BuildSubsetRequest, LastBuildDistributionQuery, QueueRequest, SubsetRequest, TestFilesResponse,
+    BUILD_SUBSETS_PATH, LAST_BUILD_DISTRIBUTION_PATH, QUEUE_PATH, SUBSET_PATH,
+};
+use crate::knapsack_client::token::TestSuiteToken;
+use crate::models::{Test, TestResult};
+use crate::queue_backend::QueueBackend;
+use crate::test_context::TestContext;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tracing::debug_span;
+
+pub mod api;
+#[cfg(feature = "async")]
+pub mod async_client;
+pub mod token;
+
+/// How the node gets its tests from Knapsack Pro
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum KnapsackMode {
+    /// Tests are fetched in batches until queue is empty
+    Queue,
+    /// Whole split for the node is fetched at once
+    Regular,
+}
+
+// Steps of getting tests of the node, shared by the blocking and the async client, which only
+// find tests and send requests when asked to
+struct TestsProtocol {
+    mode: KnapsackMode,
+    initialized: bool,
+    connecting: bool,
+}
+
+enum TestsStep {
+    // Tests of the workspace are needed, they go to `TestsProtocol::found`
+    FindTests,
+    // Request to send, its response goes to `TestsProtocol::received`
+    Send(TestsRequest),
+    Done(Vec<Test>),
+}
+
+struct TestsRequest {
+    path: &'static str,
+    action: &'static str,
+    body: TestsBody,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum TestsBody {
+    Queue(QueueRequest),
+    Subset(SubsetRequest),
+}
+
+impl TestsProtocol {
+    fn new(mode: KnapsackMode) -> Self {
+        Self {
+            mode,
+            initialized: false,
+            connecting: false,
+        }
+    }
+
+    fn start(&mut self, ci_provider_wrapper: &CiProviderWrapper) -> Result<TestsStep> {
+        let initialized = std::mem::replace(&mut self.initialized, true);
+        Ok(match self.mode {
+            // Whole split is fetched at once
+            KnapsackMode::Regular if initialized => TestsStep::Done(vec![]),
+            KnapsackMode::Regular => TestsStep::FindTests,
+            KnapsackMode::Queue if initialized => TestsStep::Send(TestsRequest {
+                path: QUEUE_PATH,
+                action: "get tests from queue",
+                body: TestsBody::Queue(QueueRequest::next_batch(ci_provider_wrapper)?),
+            }),
+            // Another node may have initialized the queue already, tests are found only if not
+            KnapsackMode::Queue => {
+                self.connecting = true;
+                TestsStep::Send(TestsRequest {
+                    path: QUEUE_PATH,
+                    action: "initialize queue",
+                    body: TestsBody::Queue(QueueRequest::connect(ci_provider_wrapper)?),
+                })
+            }
+        })
+    }
+
+    fn found(
+        &mut self,
+        ci_provider_wrapper: &CiProviderWrapper,
+        tests: &[Test],
+    ) -> Result<TestsStep> {
+        Ok(TestsStep::Send(match self.mode {
+            KnapsackMode::Regular => TestsRequest {
+                path: SUBSET_PATH,
+                action: "get build distribution subset",
+                body: TestsBody::Subset(SubsetRequest::new(ci_provider_wrapper, tests)?),
+            },
+            KnapsackMode::Queue => TestsRequest {
+                path: QUEUE_PATH,
+                action: "initialize queue",
+                body: TestsBody::Queue(QueueRequest::initialize(ci_provider_wrapper, tests)?),
+            },
+        }))
+    }
+
+    fn received(&mut self, response: TestFilesResponse) -> Result<TestsStep> {
+        if std::mem::take(&mut self.connecting) && response.is_queue_missing() {
+            return Ok(TestsStep::FindTests);
+        }
+        Ok(TestsStep::Done(response.into_tests()?))
+    }
+}
+
+/// Client of the Knapsack Pro API, or of a server compatible with it
+pub struct KnapsackClient<'a> {
+    protocol: TestsProtocol,
+    endpoint: String,
+    token: TestSuiteToken,
+    test_context: Option<&'a dyn TestContext>,
+    ci_provider_wrapper: CiProviderWrapper,
+    http: HttpConfig,
+}
+
+impl KnapsackClient<'_> {
+    /// Client of `endpoint` authenticated with test suite `token`, in queue mode
+    pub fn new<'a>(
+        endpoint: String,
+        token: TestSuiteToken,
+        test_context: &'a dyn TestContext,
+        ci_provider_wrapper: CiProviderWrapper,
+    ) -> KnapsackClient<'a> {
+        KnapsackClient {
+            protocol: TestsProtocol::new(KnapsackMode::Queue),
+            token,
+            endpoint,
+            test_context: Some(test_context),
+            ci_provider_wrapper,
+            http: HttpConfig::default(),
+        }
+    }
+
+    /// Client that can only upload results, e.g. from a journal recorded earlier
+    pub fn without_test_context(
+        endpoint: String,
+        token: TestSuiteToken,
+        ci_provider_wrapper: CiProviderWrapper,
+    ) -> KnapsackClient<'static> {
+        KnapsackClient {
+            protocol: TestsProtocol::new(KnapsackMode::Queue),
+            token,
+            endpoint,
+            test_context: None,
+            ci_provider_wrapper,
+            http: HttpConfig::default(),
+        }
+    }
+
+    /// Sets how tests are fetched
+    pub fn with_mode(self, mode: KnapsackMode) -> Self {
+        Self {
+            protocol: TestsProtocol::new(mode),
+            ..self
+        }
+    }
+
+    /// Sets proxy and CA certificates used to reach the API
+    pub fn with_http_config(self, http: HttpConfig) -> Self {
+        Self { http, ..self }
+    }
+
+    /// Checks the token with a read-only request, fails with a clear message when it is rejected
+    pub fn validate_token(&self) -> Result<()> {
+        let _span = debug_span!(
+            "api_request",
+            method = "GET",
+            path = LAST_BUILD_DISTRIBUTION_PATH
+        )
+        .entered();
+        let query = LastBuildDistributionQuery::new(&self.ci_provider_wrapper)?;
+        let request = self
+            .http
+            .blocking_client()?
+            .get(format!("{}{}", self.endpoint, LAST_BUILD_DISTRIBUTION_PATH))
+            .headers(api::headers(&self.token)?)
+            .query(&query);
+        api::check_token_status(self.http.execute(request)?.status())
+    }
+
+    fn find_tests(&self) -> Result<Vec<Test>> {
+        self.test_context
+            .context("Cannot send tests without test context")?
+            .find_tests()
+            .context("Failed to find tests")
+    }
+
+    fn post(
+        &self,
+        path: &str,
+        body: &impl Serialize,
+        action: &str,
+    ) -> Result<reqwest::blocking::Response> {
+        let _span = debug_span!("api_request", method = "POST", path).entered();
+        let request = self
+            .http
+            .blocking_client()?
+            .post(format!("{}{}", self.endpoint, path))
+            .headers(api::headers(&self.token)?)
+            .json(body);
+        self.http.send(request, action)
+    }
+
+    fn get_test_files(
+        &self,
+        path: &str,
+        body: &impl Serialize,
+        action: &str,
+    ) -> Result<TestFilesResponse> {
+        self.post(path, body, action)?
+            .json::<TestFilesResponse>()
+            .context("Failed to parse response")
+            .classify(Failure::Api)
+    }
+}
+
+impl QueueBackend for KnapsackClient<'_> {
+    fn get_tests(&mut self) -> Result<Vec<Test>> {
+        let mut step = self.protocol.start(&self.ci_provider_wrapper)?;
+        loop {
+            step = match step {
+                TestsStep::FindTests => {
+                    let tests = self.find_tests()?;
+                    self.protocol.found(&self.ci_provider_wrapper, &tests)?
+                }
+                TestsStep::Send(request) => {
+                    let response =
+                        self.get_test_files(request.path, &request.body, request.action)?;
+                    self.protocol.received(response)?
+                }
+                TestsStep::Done(tests) => return Ok(tests),
+            };
+        }
+    }
+
+    fn upload_test_results(&self, test_results: &[TestResult]) -> Result<()> {
+        let request = BuildSubsetRequest::new(&self.ci_provider_wrapper, test_results)?;
+        self.post(BUILD_SUBSETS_PATH, &request, "upload test results")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ci_providers::ci_provider_base::CiProvider;
+    use crate::models::Test;
+    use httpmock::prelude::*;
+    use serde_json::json;
+
+    #[test]
+    fn should_initialize_queue() -> Result<()> {
+        let server = MockServer::start();
+
+        let mock = server.mock(|when, then| {
+            when.path("/v1/queues/queue")
+                .header("KNAPSACK-PRO-TEST-SUITE-TOKEN", "test_api_key")
+                .json_body(json!({
+                    "can_initialize_queue": true,
+                    "attempt_connect_to_queue": true,
+                    "fixed_queue_split": true,
+                    "commit_hash": "commit_hash",
+                    "branch": "branch",
+                    "node_total": 4,
+                    "node_index": 0,
+                    "node_build_id": "build_id"
+                }));
+
+            then.status(200).json_body(json!({
+                "test_files": [
+                    {
+                        "path": "a|b|c"
+                    }
+                ]
+            }));
+        });
+
+        let finder = TestTestFinder::new();
+
+        let mut client = KnapsackClient::new(
+            server.base_url(),
+            TestSuiteToken::new("test_api_key")?,
+            &finder,
+            CiProviderWrapper::new(Box::new(TestProvider::new())),
+        );
+
+        let tests = client.get_tests()?;
+
+        mock.assert();
+
+        assert_eq!(
+            tests,
+            vec![Test {
+                package_name: "a".to_string(),
+                binary_name: "b".to_string(),
+                test_name: "c".to_string(),
+            }]
+        );
+        assert!(client.protocol.initialized);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_handle_initialized_queue() -> Result<()> {
+        let server = MockServer::start();
+
+        let mock = server.mock(|when, then| {
+            when.path("/v1/queues/queue")
+                .header("KNAPSACK-PRO-TEST-SUITE-TOKEN", "test_api_key")
+                .json_body(json!({
+                    "can_initialize_queue": true,
+                    "attempt_connect_to_queue": true,
+                    "fixed_queue_split": true,
+                    "commit_hash": "commit_hash",
+                    "branch": "branch",
+                    "node_total": 4,
+                    "node_index": 0,
+                    "node_build_id": "build_id"
+                }));
+
+            then.status(200).json_body(json!({
+                "code": "ATTEMPT_CONNECT_TO_QUEUE_FAILED"
+            }));
+        });
+
+        let mock2 = server.mock(|when, then| {
+            when.path("/v1/queues/queue")
+                .header("KNAPSACK-PRO-TEST-SUITE-TOKEN", "test_api_key")
+                .json_body(json!({
+                    "can_initialize_queue": true,
+                    "attempt_connect_to_queue": false,
+                    "fixed_queue_split": true,
+                    "commit_hash": "commit_hash",
+                    "branch": "branch",
+                    "node_total": 4,
+                    "node_index": 0,
+                    "node_build_id": "build_id",
+                    "test_files": [
+                        {
+                            "path": "pn|bn|tn"
+                        }
+                    ]
+                }));
+
+            then.status(200).json_body(json!({
+                "test_files": [
+                        {
+                            "path": "pn|bn|tn"
+                        }
+                    ]
+            }));
+        });
+
+        let finder = TestTestFinder::new();
+
+        let mut client = KnapsackClient::new(
+            server.base_url(),
+            TestSuiteToken::new("test_api_key")?,
+            &finder,
+            CiProviderWrapper::new(Box::new(TestProvider::new())),
+        );
+
+        let tests = client.get_tests()?;
+
+        mock.assert();
+        mock2.assert();
+
+        assert_eq!(
+            tests,
+            vec![Test {
+                package_name: "pn".to_string(),
+                binary_name: "bn".to_string(),
+                test_name: "tn".to_string(),
+            }]
+        );
+        assert!(client.protocol.initialized);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_get_additional_tests() -> Result<()> {
+        let server = MockServer::start();
+
+        let mock = server.mock(|when, then| {
+            when.path("/v1/queues/queue")
+                .header("KNAPSACK-PRO-TEST-SUITE-TOKEN", "test_api_key")
+                .json_body(json!({
+                    "can_initialize_queue": false,
+                    "attempt_connect_to_queue": false,
+                    "fixed_queue_split": true,
+                    "commit_hash": "commit_hash",
+                    "branch": "branch",
+                    "node_total": 4,
+                    "node_index": 0,
+                    "node_build_id": "build_id"
+                }));
+
+            then.status(200).json_body(json!({
+                "test_files": [
+                        {
+                            "path": "pn|bn|tn"
+                        }
+                    ]
+            }));
+        });
+
+        let finder = TestTestFinder::new();
+
+        let mut client = KnapsackClient::new(
+            server.base_url(),
+            TestSuiteToken::new("test_api_key")?,
+            &finder,
+            CiProviderWrapper::new(Box::new(TestProvider::new())),
+        );
+        client.protocol.initialized = true;
+
+        let tests = client.get_tests()?;
+
+        mock.assert();
+
+        assert_eq!(
+            tests,
+            vec![Test {
+                package_name: "pn".to_string(),
+                binary_name: "bn".to_string(),
+                test_name: "tn".to_string(),
+            }]
+        );
+        assert!(client.protocol.initialized);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_get_regular_mode_subset_once() -> Result<()> {
+        let server = MockServer::start();
+
+        let mock = server.mock(|when, then| {
+            when.path("/v1/build_distributions/subset")
+                .header("KNAPSACK-PRO-TEST-SUITE-TOKEN", "test_api_key")
+                .json_body(json!({
+                    "fixed_test_suite_split": true,
+                    "cache_read_attempt": false,
+                    "commit_hash": "commit_hash",
+                    "branch": "branch",
+                    "node_total": 4,
+                    "node_index": 0,
+                    "ci_build_id": "build_id",
+                    "test_files": [
+                        {
+                            "path": "pn|bn|tn"
+                        }
+                    ]
+                }));
+
+            then.status(200).json_body(json!({
+                "test_files": [
+                    {
+                        "path": "pn|bn|tn",
+                        "time_execution": 1.5
+                    }
+                ]
+            }));
+        });
+
+        let finder = TestTestFinder::new();
+
+        let mut client = KnapsackClient::new(
+            server.base_url(),
+            TestSuiteToken::new("test_api_key")?,
+            &finder,
+            CiProviderWrapper::new(Box::new(TestProvider::new())),
+        )
+        .with_mode(KnapsackMode::Regular);
+
+        let tests = client.get_tests()?;
+        let next_tests = client.get_tests()?;
+
+        mock.assert();
+
+        assert_eq!(
+            tests,
+            vec![Test {
+                package_name: "pn".to_string(),
+                binary_name: "bn".to_string(),
+                test_name: "tn".to_string(),
+            }]
+        );
+        assert!(next_tests.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_invalid_token() -> Result<()> {
+        let server = MockServer::start();
+
+        let mock = server.mock(|when, then| {
+            when.method(GET)
+                .path("/v1/build_distributions/last")
+                .header("KNAPSACK-PRO-TEST-SUITE-TOKEN", "invalid_token")
+                .query_param("node_index", "0");
+
+            then.status(403).json_body(json!({
+                "errors": ["Invalid API key"]
+            }));
+        });
+
+        let client = KnapsackClient::without_test_context(
+            server.base_url(),
+            TestSuiteToken::new("invalid_token")?,
+            CiProviderWrapper::new(Box::new(TestProvider::new())),
+        );
+
+        let error = client.validate_token().unwrap_err();
+
+        mock.assert();
+        assert_eq!(
+            error.to_string(),
+            "Invalid test suite token, Knapsack Pro rejected it with [403 Forbidden]. \
+             Check KNAPSACK_PRO_TEST_SUITE_TOKEN or --token-file"
+        );
+
+        Ok(())
+    }
+
+    struct TestProvider;
+
+    impl TestProvider {
+        fn new() -> Self {
+            Self {}
+        }
+    }
+
+    // let provider = {};
+
+    impl CiProvider for TestProvider {
+        fn get_ci_node_total(&self) -> Option<usize> {
+            Some(4)
+        }
+
+        fn get_ci_node_index(&self) -> Option<usize> {
+            Some(0)
+        }
+
+        fn get_ci_node_build_id(&self) -> Option<String> {
+            Some("build_id".into())
+        }
+
+        fn get_commit_hash(&self) -> Option<String> {
+            Some("commit_hash".into())
+        }
+
+        fn is_fixed_queue_split(&self) -> bool {
+            true
+        }
+
+        fn get_branch(&self) -> Option<String> {
+            Some("branch".into())
+        }
+    }
+
+    struct TestTestFinder;
+    impl TestTestFinder {
+        fn new() -> Self {
+            Self {}
+        }
+    }
+
+    impl TestContext for TestTestFinder {
+        fn find_tests(&self) -> Result<Vec<Test>> {
+            Ok(vec![Test {
+                package_name: "pn".to_string(),
+                binary_name: "bn".to_string(),
+                test_name: "tn".to_string(),
+            }])
+        }
+
+        fn run_tests(&self, _tests: &[Test]) -> Result<Vec<TestResult>> {
+            todo!()
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Test suite token of Knapsack Pro, kept out of `Debug` output, logs and error messages
+
+use anyhow::Context;
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+/// Test suite token, readable only through [`TestSuiteToken::expose`]
+#[derive(Clone, PartialEq)]
+pub struct TestSuiteToken(String);
+
+impl TestSuiteToken {
+    /// Token with surrounding whitespace removed, fails when empty or not usable as a header value
+    pub fn new(token: impl Into<String>) -> anyhow::Result<Self> {
+        let token = token.into();
+        let token = token.trim();
+        if token.is_empty() {
+            anyhow::bail!("Test suite token is empty");
+        }
+        // Error doesn't show the character, it may be part of the token
+        if !token.chars().all(|c| c.is_ascii_graphic()) {
+            anyhow::bail!("Test suite token contains characters that are not printable ASCII");
+        }
+        Ok(Self(token.to_string()))
+    }
+
+    /// Reads the token from a file, `-` reads it from standard input
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let token = if path == Path::new("-") {
+            let mut token = String::new();
+            std::io::stdin()
+                .read_to_string(&mut token)
+                .context("Failed to read test suite token from standard input")?;
+            token
+        } else {
+            fs::read_to_string(path)
+                .with_context(|| format!("Failed to read test suite token [{}]", path.display()))?
+        };
+        Self::new(token)
+            .with_context(|| format!("Invalid test suite token in [{}]", path.display()))
+    }
+
+    /// The token itself, to be sent to the API only
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for TestSuiteToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TestSuiteToken(<redacted>)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_trim_and_redact_token() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::write(&path, "secret-token\n")?;
+
+        let token = TestSuiteToken::load(&path)?;
+
+        assert_eq!(token.expose(), "secret-token");
+        assert_eq!(format!("{token:?}"), "TestSuiteToken(<redacted>)");
+        let error = TestSuiteToken::new("secret token").unwrap_err();
+        assert!(!format!("{error:#}").contains("secret"));
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
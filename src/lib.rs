@@ -0,0 +1,87 @@
+//! Runs [cargo-nextest](https://nexte.st) tests split between CI nodes by
+//! [Knapsack Pro](https://knapsackpro.com) or one of its self-hosted alternatives.
+//!
+//! The `cargo nextest-knapsack` binary is a thin layer over this library. Custom orchestration
+//! can be built from the same parts:
+//!
+//! - [`test_context`] discovers tests of a workspace and runs them with nextest,
+//!   [`granularity::PerBinary`] queues whole binaries instead,
+//! - [`ci_providers`] detect CI node index, total and build id,
+//! - [`queue_backend::QueueBackend`] hands out batches of tests to a node, implemented by
+//!   [`knapsack_client::KnapsackClient`], [`coordinator_client::CoordinatorClient`],
+//!   [`filesystem_queue::FilesystemQueue`] and [`offline_split::OfflineSplit`],
+//! - [`models`] holds tests and their results, [`journal`] keeps results on disk,
+//! - [`failure::Failure`] tells test failures apart from build, API and configuration ones.
+//!
+//! With the `async` feature, `knapsack_client::async_client::AsyncKnapsackClient` implements
+//! `queue_backend::AsyncQueueBackend` for use inside async runtimes.
+//!
+//! ```no_run
+//! use cargo_nextest_knapsack::ci_providers::ci_provider_wrapper::CiProviderWrapper;
+//! use cargo_nextest_knapsack::ci_providers::github_actions::GithubActionsCiProvider;
+//! use cargo_nextest_knapsack::knapsack_client::token::TestSuiteToken;
+//! use cargo_nextest_knapsack::knapsack_client::KnapsackClient;
+//! use cargo_nextest_knapsack::queue_backend::QueueBackend;
+//! use cargo_nextest_knapsack::test_context::{DefaultTestContext, TestContext, TestSelection};
+//! use std::path::Path;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let context = DefaultTestContext::new(Path::new("."), &TestSelection::default())?;
+//! let mut client = KnapsackClient::new(
+//!     "https://api.knapsackpro.com".into(),
+//!     TestSuiteToken::new(std::env::var("KNAPSACK_PRO_TEST_SUITE_TOKEN")?)?,
+//!     &context,
+//!     CiProviderWrapper::new(Box::new(GithubActionsCiProvider)),
+//! );
+//!
+//! let mut results = vec![];
+//! loop {
+//!     let tests = client.get_tests()?;
+//!     if tests.is_empty() {
+//!         break;
+//!     }
+//!     results.append(&mut context.run_tests(&tests)?);
+//! }
+//! client.upload_test_results(&results)?;
+//! # Ok(())
+//! # }
+//! ```
+
+#![warn(missing_docs)]
+
+/// Detection of CI node index, node total and build id.
+pub mod ci_providers;
+/// Queue backend talking to the `coordinator` subcommand.
+pub mod coordinator_client;
+/// Discovery and running of doctests, which nextest doesn't support.
+pub mod doctests;
+/// Kinds of failures and exit codes telling them apart.
+pub mod failure;
+/// Queue backend sharing batches through a directory.
+pub mod filesystem_queue;
+/// Queuing whole test binaries instead of single tests.
+pub mod granularity;
+/// Proxy, CA certificates and failure descriptions of API clients.
+pub mod http_client;
+/// Results of a node kept on disk until they are uploaded.
+pub mod journal;
+/// Queue backend talking to the Knapsack Pro API.
+pub mod knapsack_client;
+/// Tests, their results and conversions to Knapsack and nextest names.
+pub mod models;
+/// Split computed from timings of previous runs, without any server.
+pub mod offline_split;
+/// Common interface of queue backends.
+pub mod queue_backend;
+/// Record of batches received by a node, replayed when the node is retried.
+pub mod replay;
+/// Minimal HTTP server of the self-hosted Knapsack API and coordinator.
+pub mod server;
+/// Handling of Ctrl+C and termination signals.
+pub mod shutdown;
+/// Discovery and running of tests with nextest.
+pub mod test_context;
+#[cfg(test)]
+mod test_utils;
+/// Timing history and the algorithms splitting tests by it.
+pub mod timings;
@@ -0,0 +1,77 @@
+use crate::cli::{LogArgs, LogFormat};
+use anyhow::Context;
+use cargo_nextest_knapsack::test_context::KNAPSACK_DIRECTORY;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, Layer, Registry};
+
+// Events of dependencies (e.g. HTTP/2 internals) are left out, library and binary share the name
+const TARGET: &str = "cargo_nextest_knapsack";
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+// Log goes to stderr, so output of commands (e.g. `plan`) stays clean, and to the log file
+pub(crate) fn init(args: &LogArgs) -> anyhow::Result<()> {
+    let level = match (args.verbose, args.quiet) {
+        (0, 0) => LevelFilter::INFO,
+        (1, _) => LevelFilter::DEBUG,
+        (_, 0) => LevelFilter::TRACE,
+        (_, 1) => LevelFilter::WARN,
+        _ => LevelFilter::ERROR,
+    };
+
+    let path = args
+        .log_file
+        .clone()
+        .unwrap_or_else(|| Path::new(KNAPSACK_DIRECTORY).join("nextest-knapsack.log"));
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory [{}]", parent.display()))?;
+    }
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open log file [{}]", path.display()))?;
+
+    let terminal = layer(
+        args.log_format,
+        std::io::stderr,
+        std::io::stderr().is_terminal(),
+        level,
+    );
+    let file = layer(
+        args.log_format,
+        Mutex::new(file),
+        false,
+        level.max(LevelFilter::DEBUG),
+    );
+    tracing_subscriber::registry()
+        .with(vec![terminal, file])
+        .try_init()
+        .context("Failed to initialize logging")
+}
+
+fn layer<W>(format: LogFormat, writer: W, ansi: bool, level: LevelFilter) -> BoxedLayer
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = fmt::layer().with_writer(writer).with_ansi(ansi);
+    let layer = match format {
+        LogFormat::Text => layer.with_target(false).boxed(),
+        LogFormat::Json => layer.json().with_span_list(true).boxed(),
+    };
+    layer
+        .with_filter(Targets::new().with_target(TARGET, level))
+        .boxed()
+}
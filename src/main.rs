@@ -1,46 +1,67 @@
-use crate::ci_providers::ci_provider_wrapper::CiProviderWrapper;
-use crate::ci_providers::github_actions::GithubActionsCiProvider;
-use crate::knapsack_client::KnapsackClient;
-use crate::test_context::{DefaultTestContext, TestContext};
-use anyhow::Context;
-use std::path::Path;
+use crate::cli::{Cli, Command};
+use crate::config::Config;
+use cargo_nextest_knapsack::failure::{Classify, Failure};
+use std::process::ExitCode;
 
-mod ci_providers;
-mod knapsack_client;
-mod models;
-mod test_context;
+mod cli;
+mod commands;
+mod config;
+mod logging;
 
-fn main() -> anyhow::Result<()> {
-    let knapsack_api_key = std::env::var("KNAPSACK_PRO_TEST_SUITE_TOKEN")
-        .context("Could not find KNAPSACK_PRO_TEST_SUITE_TOKEN environment variable")?;
+// Exit code of a panic, `EX_SOFTWARE` of sysexits. Rust exits with 101 on panic, which is the
+// code of a build failure.
+const PANIC_EXIT_CODE: i32 = 70;
 
-    println!("Caching workspace info");
-    let context = DefaultTestContext::new(Path::new("."))?;
-    println!("Workspace info cached");
-    let ci_provider_wrapper = CiProviderWrapper::new(Box::new(GithubActionsCiProvider {}));
+// Errors are printed the way `main` returning `anyhow::Result` does, exit code tells their kind
+fn main() -> ExitCode {
+    // Panic of any thread is a bug, the message is printed by the default hook first
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        std::process::exit(PANIC_EXIT_CODE);
+    }));
 
-    let mut client = KnapsackClient::new(
-        "https://api.knapsackpro.com".into(),
-        knapsack_api_key,
-        &context,
-        ci_provider_wrapper,
-    );
-
-    let mut results = vec![];
-
-    loop {
-        let tests = client.get_tests()?;
-        println!("Tests: {:?}", tests);
-        if tests.is_empty() {
-            break;
+    match execute() {
+        Ok(exit_code) => exit_code,
+        Err(error) => {
+            eprintln!("Error: {error:?}");
+            ExitCode::from(Failure::of(&error).map_or(1, Failure::exit_code))
         }
-
-        let mut local_results =
-            context.run_tests(&tests).context("Failed to run tests")?;
-        results.append(&mut local_results);
     }
+}
 
-    client.upload_test_results(&results)?;
+fn execute() -> anyhow::Result<ExitCode> {
+    let (cli, matches) = Cli::parse_args();
+    logging::init(&cli.log).classify(Failure::Configuration)?;
+    let profile = Config::load(cli.config.as_deref())
+        .classify(Failure::Configuration)?
+        .profile(cli.profile.as_deref());
+    let matches = matches
+        .subcommand()
+        .map_or(&matches, |(_, matches)| matches);
 
-    Ok(())
+    match cli.command.unwrap_or(Command::Run(cli.run)) {
+        Command::Run(mut args) => {
+            profile.apply_to_run(&mut args, matches);
+            commands::run::run(args)
+        }
+        Command::Upload(mut args) => {
+            profile.apply_to_knapsack(&mut args.knapsack, matches);
+            commands::upload::upload(args).map(|_| ExitCode::SUCCESS)
+        }
+        Command::Plan(mut args) => {
+            profile.apply_to_plan(&mut args, matches);
+            commands::plan::plan(args).map(|_| ExitCode::SUCCESS)
+        }
+        Command::Serve(args) => commands::serve::serve(args).map(|_| ExitCode::SUCCESS),
+        Command::Coordinator(args) => {
+            commands::coordinator::coordinator(args).map(|_| ExitCode::SUCCESS)
+        }
+        Command::Report(mut args) => {
+            profile.apply_to_report(&mut args, matches);
+            commands::report::report(args).map(|_| ExitCode::SUCCESS)
+        }
+        Command::Simulate(args) => commands::simulate::simulate(args),
+        Command::BuildId(args) => commands::build_id::build_id(args).map(|_| ExitCode::SUCCESS),
+    }
 }
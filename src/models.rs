@@ -1,35 +1,103 @@
 use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
 
-#[derive(Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]
-pub(crate) struct Test {
-    pub(crate) package_name: String,
-    pub(crate) binary_name: String,
-    pub(crate) test_name: String,
+/// Single test of a nextest binary, or a source file with doctests
+#[derive(Clone, Debug, Ord, PartialOrd, Eq, PartialEq, Serialize)]
+pub struct Test {
+    /// Cargo package of the test
+    pub package_name: String,
+    /// Name of the test binary within the package
+    pub binary_name: String,
+    /// Full name of the test within the binary
+    pub test_name: String,
 }
 
 impl Test {
-    pub(crate) fn to_knapsack_file(&self) -> String {
+    /// Path of the test in Knapsack, `package|binary|test`
+    pub fn to_knapsack_file(&self) -> String {
         format!(
             "{}|{}|{}",
             self.package_name, self.binary_name, self.test_name
         )
     }
 
-    pub(crate) fn to_nextest_name(&self) -> String {
+    /// Name of the test in nextest, `package::binary$test`
+    pub fn to_nextest_name(&self) -> String {
         format!(
             "{}::{}${}",
             self.package_name, self.binary_name, self.test_name
         )
     }
 
-    pub(crate) fn to_nextest_filter(&self) -> Vec<String> {
-        vec![
-            "-E".into(),
-            format!("package({}) & test(={})", self.package_name, self.test_name),
-        ]
+    /// Single filterset matching all given tests, to be passed to `cargo nextest run -E`
+    pub fn to_nextest_filterset(tests: &[Test]) -> String {
+        Self::to_nextest_filtersets(tests, usize::MAX)
+            .pop()
+            .unwrap_or_else(|| "none()".into())
     }
 
-    pub(crate) fn from_knapsack_file(line: &str) -> anyhow::Result<Self> {
+    /// Filtersets matching all given tests, each shorter than `max_length` unless it matches
+    /// a single test. Tests of the same binary share a single `package() & binary()` group.
+    pub fn to_nextest_filtersets(tests: &[Test], max_length: usize) -> Vec<String> {
+        let mut tests = tests.iter().collect::<Vec<_>>();
+        tests.sort();
+        tests.dedup();
+
+        let mut filtersets = vec![];
+        let mut groups: Vec<(&Test, Vec<String>)> = vec![];
+        let mut length = 0;
+        for test in tests {
+            let test_expression = format!("test(={})", test.test_name);
+            let same_group = groups.last().is_some_and(|(first, _)| {
+                first.package_name == test.package_name && first.binary_name == test.binary_name
+            });
+            let added_length = if same_group {
+                " | ".len() + test_expression.len()
+            } else {
+                let separator = if groups.is_empty() { 0 } else { " | ".len() };
+                separator + Self::group_expression(test, &test_expression).len()
+            };
+
+            if !groups.is_empty() && length + added_length > max_length {
+                filtersets.push(Self::groups_expression(&groups));
+                groups.clear();
+                length = Self::group_expression(test, &test_expression).len();
+                groups.push((test, vec![test_expression]));
+            } else if same_group {
+                length += added_length;
+                groups.last_mut().unwrap().1.push(test_expression);
+            } else {
+                length += added_length;
+                groups.push((test, vec![test_expression]));
+            }
+        }
+        if !groups.is_empty() {
+            filtersets.push(Self::groups_expression(&groups));
+        }
+
+        filtersets
+    }
+
+    fn group_expression(test: &Test, test_expressions: &str) -> String {
+        format!(
+            "(package({}) & binary(={}) & ({}))",
+            test.package_name, test.binary_name, test_expressions
+        )
+    }
+
+    fn groups_expression(groups: &[(&Test, Vec<String>)]) -> String {
+        groups
+            .iter()
+            .map(|(test, test_expressions)| {
+                Self::group_expression(test, &test_expressions.join(" | "))
+            })
+            .collect::<Vec<_>>()
+            .join(" | ")
+    }
+
+    /// Parses path of the test in Knapsack, inverse of [`Test::to_knapsack_file`]
+    pub fn from_knapsack_file(line: &str) -> anyhow::Result<Self> {
         let regex = Regex::new(r"(.*)\|(.*)\|(.*)")?;
         let Some(caps) = regex.captures(line) else {
             anyhow::bail!("Invalid test file format: {}", line)
@@ -43,8 +111,86 @@ impl Test {
     }
 }
 
+/// Test that was run, with its execution time
 #[derive(Debug, PartialEq)]
-pub(crate) struct TestResult {
-    pub(crate) test: Test,
-    pub(crate) exec_time: f64,
+pub struct TestResult {
+    /// Test that was run
+    pub test: Test,
+    /// Execution time in seconds of the last attempt, capped by nextest when the test timed out
+    pub exec_time: f64,
+    /// Last attempt of the test, tests retried by nextest pass or fail on a later one
+    pub attempt: u32,
+    /// How the last attempt ended
+    pub outcome: TestOutcome,
+    /// When the last attempt finished, known for tests nextest ran in this process. Tests run in
+    /// parallel, so wall time of several tests is not the sum of their times.
+    pub finished_at: Option<Instant>,
+}
+
+impl TestResult {
+    /// Whether the test failed before it passed on a retry
+    pub fn is_flaky(&self) -> bool {
+        self.outcome == TestOutcome::Passed && self.attempt > 1
+    }
+}
+
+/// How a test ended. Tests that crashed are reported by nextest as failed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TestOutcome {
+    /// Test passed
+    #[default]
+    Passed,
+    /// Test failed or crashed
+    Failed,
+    /// Test was stopped after the timeout of the nextest profile (`slow-timeout.terminate-after`)
+    TimedOut,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test(package_name: &str, binary_name: &str, test_name: &str) -> Test {
+        Test {
+            package_name: package_name.into(),
+            binary_name: binary_name.into(),
+            test_name: test_name.into(),
+        }
+    }
+
+    #[test]
+    fn should_create_filterset_for_tests() {
+        let tests = vec![
+            test("b", "tests", "second"),
+            test("a", "a", "tests::first"),
+            test("a", "a", "tests::third"),
+        ];
+
+        assert_eq!(
+            Test::to_nextest_filterset(&tests),
+            "(package(a) & binary(=a) & (test(=tests::first) | test(=tests::third))) \
+             | (package(b) & binary(=tests) & (test(=second)))"
+        );
+        assert_eq!(Test::to_nextest_filterset(&[]), "none()");
+    }
+
+    #[test]
+    fn should_split_long_filtersets() {
+        let tests = ["a", "b", "c", "d", "e"].map(|name| test("p", "b", name));
+
+        let filtersets = Test::to_nextest_filtersets(&tests, 50);
+
+        assert_eq!(
+            filtersets,
+            vec![
+                "(package(p) & binary(=b) & (test(=a) | test(=b)))",
+                "(package(p) & binary(=b) & (test(=c) | test(=d)))",
+                "(package(p) & binary(=b) & (test(=e)))",
+            ]
+        );
+        assert!(filtersets.iter().all(|filterset| filterset.len() <= 50));
+        // Test that doesn't fit on its own still gets a filterset
+        assert_eq!(Test::to_nextest_filtersets(&tests[..1], 10).len(), 1);
+    }
 }
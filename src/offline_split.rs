@@ -0,0 +1,203 @@
+use crate::ci_providers::ci_provider_wrapper::CiProviderWrapper;
+use crate::journal::Journal;
+use crate::models::{Test, TestResult};
+use crate::queue_backend::QueueBackend;
+use crate::test_context::TestContext;
+use crate::timings::{partition_longest_first, TimingHistory};
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Static split computed locally from timings of previous runs, needs no API. Every node
+/// computes the same longest-processing-time partition and runs only its own part.
+pub struct OfflineSplit<'a> {
+    timings: TimingHistory,
+    test_context: &'a dyn TestContext,
+    ci_provider_wrapper: CiProviderWrapper,
+    done: bool,
+}
+
+impl OfflineSplit<'_> {
+    /// Split of tests found by `test_context` by given timings
+    pub fn new<'a>(
+        timings: TimingHistory,
+        test_context: &'a dyn TestContext,
+        ci_provider_wrapper: CiProviderWrapper,
+    ) -> OfflineSplit<'a> {
+        OfflineSplit {
+            timings,
+            test_context,
+            ci_provider_wrapper,
+            done: false,
+        }
+    }
+}
+
+impl QueueBackend for OfflineSplit<'_> {
+    fn get_tests(&mut self) -> Result<Vec<Test>> {
+        if self.done {
+            return Ok(vec![]);
+        }
+        self.done = true;
+
+        let node_total = self
+            .ci_provider_wrapper
+            .get_ci_node_total()
+            .context("Failed to get node total")?;
+        let node_index = self
+            .ci_provider_wrapper
+            .get_ci_node_index()
+            .context("Failed to get node index")?;
+        if node_index >= node_total {
+            anyhow::bail!("Node index {node_index} is out of range for {node_total} nodes");
+        }
+
+        let tests = self
+            .test_context
+            .find_tests()
+            .context("Failed to find tests")?;
+        let paths = tests
+            .iter()
+            .map(|test| test.to_knapsack_file())
+            .collect::<Vec<_>>();
+
+        partition_longest_first(self.timings.longest_first(&paths), node_total)
+            .swap_remove(node_index)
+            .into_iter()
+            .map(|(path, _)| {
+                Test::from_knapsack_file(&path)
+                    .with_context(|| format!("Failed to parse test file: {}", path))
+            })
+            .collect()
+    }
+
+    // Results are kept only in the journal, which can be used as history of the next run
+    fn upload_test_results(&self, _test_results: &[TestResult]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads timings from journals of this tool or nextest JUnit reports (`.xml`), later files
+/// take precedence. Missing files are skipped, tests without history get estimated time.
+pub fn load_history(paths: &[PathBuf]) -> Result<TimingHistory> {
+    let mut timings = TimingHistory::default();
+    for path in paths {
+        if !path.exists() {
+            warn!(
+                "Timings file [{}] does not exist, skipping it",
+                path.display()
+            );
+            continue;
+        }
+        if path.extension().is_some_and(|extension| extension == "xml") {
+            read_junit(path, &mut timings)?;
+        } else {
+            for result in Journal::read(path)?.results {
+                timings.record(&result.test.to_knapsack_file(), result.exec_time);
+            }
+        }
+    }
+    Ok(timings)
+}
+
+fn read_junit(path: &Path, timings: &mut TimingHistory) -> Result<()> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read JUnit report [{}]", path.display()))?;
+    let document = roxmltree::Document::parse(&content)
+        .with_context(|| format!("Failed to parse JUnit report [{}]", path.display()))?;
+
+    let test_cases = document
+        .descendants()
+        .filter(|node| node.has_tag_name("testcase"))
+        .filter(|node| !node.children().any(|child| child.has_tag_name("skipped")));
+    for test_case in test_cases {
+        let (Some(binary_id), Some(name), Some(time)) = (
+            test_case.attribute("classname"),
+            test_case.attribute("name"),
+            test_case.attribute("time"),
+        ) else {
+            continue;
+        };
+        let time = time.parse::<f64>().with_context(|| {
+            format!("Invalid time [{time}] in JUnit report [{}]", path.display())
+        })?;
+        timings.record(&junit_knapsack_file(binary_id, name), time);
+    }
+    Ok(())
+}
+
+// nextest uses binary id as class name: `package` for library, `package::test` for
+// integration tests and `package::kind/name` for binaries, examples and benchmarks
+fn junit_knapsack_file(binary_id: &str, test_name: &str) -> String {
+    let (package_name, binary_name) = match binary_id.split_once("::") {
+        Some((package_name, binary)) => (
+            package_name.to_string(),
+            binary
+                .rsplit_once('/')
+                .map_or(binary, |(_, name)| name)
+                .to_string(),
+        ),
+        None => (binary_id.to_string(), binary_id.replace('-', "_")),
+    };
+    format!("{package_name}|{binary_name}|{test_name}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{FixedTests, TestNode};
+
+    #[test]
+    fn should_split_tests_by_junit_timings() -> Result<()> {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&directory)?;
+        let report = directory.join("junit.xml");
+        fs::write(
+            &report,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="nextest-run" tests="4">
+    <testsuite name="pn::bn" tests="4">
+        <testcase name="a" classname="pn::bn" time="5.0"/>
+        <testcase name="b" classname="pn::bn" time="3.0"/>
+        <testcase name="c" classname="pn::bn" time="2.0">
+            <failure type="test failure"/>
+        </testcase>
+        <testcase name="d" classname="pn::bn" time="0.0">
+            <skipped/>
+        </testcase>
+    </testsuite>
+    <testsuite name="my-crate" tests="1">
+        <testcase name="tests::e" classname="my-crate" time="1.0"/>
+    </testsuite>
+</testsuites>"#,
+        )?;
+
+        let timings = load_history(&[report, directory.join("missing.jsonl")])?;
+        assert_eq!(timings.get("pn|bn|c"), Some(2.0));
+        assert_eq!(timings.get("pn|bn|d"), None);
+        assert_eq!(timings.get("my-crate|my_crate|tests::e"), Some(1.0));
+
+        // Unknown test `d` is estimated with average time of 2.75s
+        let tests = FixedTests::new(&["a", "b", "c", "d"]);
+        let names = |node_index| -> Result<Vec<String>> {
+            let mut split = OfflineSplit::new(
+                timings.clone(),
+                &tests,
+                CiProviderWrapper::new(Box::new(TestNode::new(node_index, 2))),
+            );
+            let names = split
+                .get_tests()?
+                .into_iter()
+                .map(|test| test.test_name)
+                .collect();
+            assert!(split.get_tests()?.is_empty());
+            Ok(names)
+        };
+        assert_eq!(names(0)?, vec!["a", "c"]);
+        assert_eq!(names(1)?, vec!["b", "d"]);
+
+        fs::remove_dir_all(directory)?;
+        Ok(())
+    }
+}
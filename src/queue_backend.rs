@@ -0,0 +1,23 @@
+use crate::models::{Test, TestResult};
+#[cfg(feature = "async")]
+use std::future::Future;
+
+/// Source of tests for the node: Knapsack Pro or a self-hosted alternative
+pub trait QueueBackend {
+    /// Next batch of tests to run, empty when there is nothing left for this node
+    fn get_tests(&mut self) -> anyhow::Result<Vec<Test>>;
+    /// Stores results of tests run by this node, used for splits of later builds
+    fn upload_test_results(&self, test_results: &[TestResult]) -> anyhow::Result<()>;
+}
+
+/// Async variant of [`QueueBackend`], futures are `Send` so they can be spawned on a runtime
+#[cfg(feature = "async")]
+pub trait AsyncQueueBackend {
+    /// Next batch of tests to run, empty when there is nothing left for this node
+    fn get_tests(&mut self) -> impl Future<Output = anyhow::Result<Vec<Test>>> + Send;
+    /// Stores results of tests run by this node, used for splits of later builds
+    fn upload_test_results(
+        &self,
+        test_results: &[TestResult],
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
@@ -0,0 +1,248 @@
+use crate::journal::{node_file_key, path_safe, JournalNode};
+use crate::models::{Test, TestResult};
+use crate::queue_backend::QueueBackend;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+// Record is a JSON Lines file with one batch per line, written before the batch is run,
+// so a batch interrupted by a crash of the node is replayed as well
+#[derive(Serialize, Deserialize)]
+struct RecordedBatch {
+    test_files: Vec<String>,
+}
+
+/// Batches of tests received by a node, kept so a retry of the node runs the same tests.
+/// Queue mode hands every test out once, so the queue has nothing left for a retried node.
+pub struct BatchRecord {
+    path: PathBuf,
+    file: File,
+}
+
+impl BatchRecord {
+    /// Default path of the record of given node in `directory`
+    pub fn path_for(directory: &Path, node: &JournalNode) -> PathBuf {
+        directory.join(format!("batches-{}.jsonl", node_file_key(node)))
+    }
+
+    /// Record at `path` chosen by the user, `-<suite>` is added to its name for a suite, so
+    /// suites of the node don't share it
+    pub fn path_of_suite(path: &Path, node: &JournalNode) -> PathBuf {
+        let Some(suite) = &node.suite else {
+            return path.to_path_buf();
+        };
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match path.extension() {
+            Some(extension) => format!(
+                "{stem}-{}.{}",
+                path_safe(suite),
+                extension.to_string_lossy()
+            ),
+            None => format!("{stem}-{}", path_safe(suite)),
+        };
+        path.with_file_name(name)
+    }
+
+    /// Starts a new record, previous content is discarded
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory [{}]", parent.display()))?;
+        }
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create batch record [{}]", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// Appends a received batch
+    pub fn record(&mut self, tests: &[Test]) -> anyhow::Result<()> {
+        let batch = RecordedBatch {
+            test_files: tests.iter().map(Test::to_knapsack_file).collect(),
+        };
+        let line = serde_json::to_string(&batch).context("Failed to serialize batch")?;
+        writeln!(self.file, "{}", line)
+            .and_then(|_| self.file.sync_data())
+            .with_context(|| format!("Failed to write batch record [{}]", self.path.display()))
+    }
+
+    /// Reads recorded batches, tolerates a truncated last line
+    pub fn read(path: &Path) -> anyhow::Result<Vec<Vec<Test>>> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open batch record [{}]", path.display()))?;
+
+        let mut batches = vec![];
+        for (index, line) in BufReader::new(file).lines().enumerate() {
+            let line =
+                line.with_context(|| format!("Failed to read batch record [{}]", path.display()))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let batch: RecordedBatch = match serde_json::from_str(&line) {
+                Ok(batch) => batch,
+                // Node may have been killed while writing the last line
+                Err(e) if e.is_eof() => break,
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!(
+                            "Invalid batch in record [{}] line {}",
+                            path.display(),
+                            index + 1
+                        )
+                    })
+                }
+            };
+            batches.push(
+                batch
+                    .test_files
+                    .iter()
+                    .map(|file| {
+                        Test::from_knapsack_file(file)
+                            .with_context(|| format!("Failed to parse test file: {}", file))
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+            );
+        }
+        Ok(batches)
+    }
+}
+
+/// Hands out recorded batches instead of taking tests from the queue, results are uploaded by
+/// the wrapped backend
+pub struct ReplayQueue<'a> {
+    batches: VecDeque<Vec<Test>>,
+    backend: Box<dyn QueueBackend + 'a>,
+}
+
+impl<'a> ReplayQueue<'a> {
+    /// Replays `batches` in the order they were recorded
+    pub fn new(batches: Vec<Vec<Test>>, backend: Box<dyn QueueBackend + 'a>) -> Self {
+        Self {
+            batches: batches.into(),
+            backend,
+        }
+    }
+}
+
+impl QueueBackend for ReplayQueue<'_> {
+    fn get_tests(&mut self) -> anyhow::Result<Vec<Test>> {
+        Ok(self.batches.pop_front().unwrap_or_default())
+    }
+
+    fn upload_test_results(&self, test_results: &[TestResult]) -> anyhow::Result<()> {
+        self.backend.upload_test_results(test_results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TestOutcome;
+    use std::cell::Cell;
+
+    // Queue that must not be asked for tests
+    struct UploadOnly<'a> {
+        uploaded: &'a Cell<usize>,
+    }
+
+    impl QueueBackend for UploadOnly<'_> {
+        fn get_tests(&mut self) -> anyhow::Result<Vec<Test>> {
+            anyhow::bail!("Queue was asked for tests")
+        }
+
+        fn upload_test_results(&self, test_results: &[TestResult]) -> anyhow::Result<()> {
+            self.uploaded.set(self.uploaded.get() + test_results.len());
+            Ok(())
+        }
+    }
+
+    fn test(test_name: &str) -> Test {
+        Test {
+            package_name: "pn".into(),
+            binary_name: "bn".into(),
+            test_name: test_name.into(),
+        }
+    }
+
+    #[test]
+    fn should_replay_recorded_batches() -> anyhow::Result<()> {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let node = JournalNode {
+            commit_hash: "commit_hash".into(),
+            branch: "branch".into(),
+            node_total: 2,
+            node_index: 1,
+            node_build_id: "build/id".into(),
+            fixed_queue_split: true,
+            suite: None,
+        };
+        let path = BatchRecord::path_for(&directory, &node);
+        assert_eq!(path, directory.join("batches-build_2Fid-1.jsonl"));
+
+        let mut record = BatchRecord::create(&path)?;
+        record.record(&[test("a"), test("b")])?;
+        record.record(&[test("c")])?;
+        // Node killed while recording
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(b"{\"test_files\": [\"pn|bn|")?;
+
+        let uploaded = Cell::new(0);
+        let mut replay = ReplayQueue::new(
+            BatchRecord::read(&path)?,
+            Box::new(UploadOnly {
+                uploaded: &uploaded,
+            }),
+        );
+        assert_eq!(replay.get_tests()?, vec![test("a"), test("b")]);
+        assert_eq!(replay.get_tests()?, vec![test("c")]);
+        assert!(replay.get_tests()?.is_empty());
+
+        replay.upload_test_results(&[TestResult {
+            test: test("a"),
+            exec_time: 1.0,
+            attempt: 1,
+            outcome: TestOutcome::Passed,
+            finished_at: None,
+        }])?;
+        assert_eq!(uploaded.get(), 1);
+
+        fs::remove_dir_all(directory)?;
+        Ok(())
+    }
+
+    #[test]
+    fn should_add_suite_to_record_path() {
+        let node = JournalNode {
+            commit_hash: "commit_hash".into(),
+            branch: "branch".into(),
+            node_total: 2,
+            node_index: 1,
+            node_build_id: "build_id".into(),
+            fixed_queue_split: true,
+            suite: None,
+        };
+        let path = Path::new("cache/batches.jsonl");
+        assert_eq!(BatchRecord::path_of_suite(path, &node), path);
+
+        let suite = JournalNode {
+            suite: Some("unit".into()),
+            ..node
+        };
+        assert_eq!(
+            BatchRecord::path_of_suite(path, &suite),
+            Path::new("cache/batches-unit.jsonl")
+        );
+        assert_eq!(
+            BatchRecord::path_of_suite(Path::new("batches"), &suite),
+            Path::new("batches-unit")
+        );
+    }
+}
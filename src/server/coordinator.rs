@@ -0,0 +1,251 @@
+use crate::coordinator_client::{
+    BatchRequest, BatchResponse, ResultsRequest, BATCH_PATH, BUILD_NOT_INITIALIZED, RESULTS_PATH,
+};
+use crate::knapsack_client::api::TestFile;
+use crate::server::{parse_body, Handler, HttpResponse};
+use crate::timings::{partition_longest_first, TimingHistory};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// Longer than any build, schedules of finished or abandoned builds don't pile up
+const DEFAULT_SCHEDULE_TTL: Duration = Duration::from_secs(6 * 60 * 60);
+
+// Tests of a build are split up front with longest-processing-time bin packing. Node takes
+// half of its remaining work at a time; once it runs out it steals the shortest tests from
+// the node with the most work left.
+struct Schedule {
+    nodes: Vec<VecDeque<(String, f64)>>,
+    last_request: Instant,
+}
+
+/// Coordinates builds of a single test suite without Knapsack Pro
+pub struct Coordinator {
+    timings_path: PathBuf,
+    timings: TimingHistory,
+    schedules: HashMap<String, Schedule>,
+    schedule_ttl: Duration,
+}
+
+impl Coordinator {
+    /// Coordinator keeping timings in `storage`
+    pub fn new(storage: &Path) -> anyhow::Result<Self> {
+        let timings_path = storage.join("timings.json");
+        let timings = TimingHistory::load(&timings_path)?;
+        Ok(Self {
+            timings_path,
+            timings,
+            schedules: HashMap::new(),
+            schedule_ttl: DEFAULT_SCHEDULE_TTL,
+        })
+    }
+
+    /// Schedule of a build is dropped when none of its nodes asked for tests for this long
+    pub fn with_schedule_ttl(mut self, schedule_ttl: Duration) -> Self {
+        self.schedule_ttl = schedule_ttl;
+        self
+    }
+
+    fn batch(&mut self, body: &[u8]) -> Result<HttpResponse, HttpResponse> {
+        let request = parse_body::<BatchRequest>(body)?;
+        if request.node_index >= request.node_total {
+            return Err(HttpResponse::error(
+                422,
+                format!(
+                    "Node index {} is out of range for {} nodes",
+                    request.node_index, request.node_total
+                ),
+            ));
+        }
+
+        let schedule_ttl = self.schedule_ttl;
+        self.schedules
+            .retain(|_, schedule| schedule.last_request.elapsed() <= schedule_ttl);
+
+        if !self.schedules.contains_key(&request.build_id) {
+            let Some(test_files) = request.test_files else {
+                return Ok(HttpResponse::ok(json!(BatchResponse {
+                    code: Some(BUILD_NOT_INITIALIZED.into()),
+                    test_files: vec![],
+                })));
+            };
+            let paths = test_files
+                .into_iter()
+                .map(|file| file.path)
+                .collect::<Vec<_>>();
+            let schedule = Schedule::new(&self.timings, &paths, request.node_total);
+            self.schedules.insert(request.build_id.clone(), schedule);
+        }
+
+        let schedule = &mut self.schedules.get_mut(&request.build_id).unwrap();
+        schedule.last_request = Instant::now();
+        if schedule.nodes.len() != request.node_total {
+            return Err(HttpResponse::error(
+                422,
+                format!(
+                    "Build [{}] was started with {} nodes",
+                    request.build_id,
+                    schedule.nodes.len()
+                ),
+            ));
+        }
+
+        let test_files = schedule
+            .next_batch(request.node_index)
+            .into_iter()
+            .map(|(path, time)| TestFile {
+                path,
+                time_execution: Some(time),
+            })
+            .collect::<Vec<_>>();
+        Ok(HttpResponse::ok(json!(BatchResponse {
+            code: None,
+            test_files,
+        })))
+    }
+
+    fn results(&mut self, body: &[u8]) -> Result<HttpResponse, HttpResponse> {
+        let request = parse_body::<ResultsRequest>(body)?;
+        for file in request.test_files {
+            let time = file.time_execution.ok_or_else(|| {
+                HttpResponse::error(422, format!("Missing time_execution for [{}]", file.path))
+            })?;
+            self.timings.record(&file.path, time);
+        }
+        self.timings
+            .save(&self.timings_path)
+            .map_err(|e| HttpResponse::error(500, format!("{e:#}")))?;
+        Ok(HttpResponse::ok(json!({})))
+    }
+}
+
+impl Handler for Coordinator {
+    fn handle(
+        &mut self,
+        method: &str,
+        path: &str,
+        _token: Option<&str>,
+        body: &[u8],
+    ) -> HttpResponse {
+        let response = match (method, path) {
+            ("POST", BATCH_PATH) => self.batch(body),
+            ("POST", RESULTS_PATH) => self.results(body),
+            _ => Err(HttpResponse::error(
+                404,
+                format!("Unknown endpoint [{method} {path}]"),
+            )),
+        };
+        response.unwrap_or_else(|e| e)
+    }
+}
+
+impl Schedule {
+    fn new(timings: &TimingHistory, paths: &[String], node_total: usize) -> Self {
+        Self {
+            nodes: partition_longest_first(timings.longest_first(paths), node_total)
+                .into_iter()
+                .map(VecDeque::from)
+                .collect(),
+            last_request: Instant::now(),
+        }
+    }
+
+    fn next_batch(&mut self, node_index: usize) -> Vec<(String, f64)> {
+        if self.nodes[node_index].is_empty() {
+            let victim = (0..self.nodes.len())
+                .max_by(|a, b| {
+                    Self::remaining(&self.nodes[*a]).total_cmp(&Self::remaining(&self.nodes[*b]))
+                })
+                .unwrap();
+            return Self::take_half(&mut self.nodes[victim], true);
+        }
+        Self::take_half(&mut self.nodes[node_index], false)
+    }
+
+    fn remaining(tests: &VecDeque<(String, f64)>) -> f64 {
+        tests.iter().map(|(_, time)| time).sum()
+    }
+
+    // Takes tests until half of the time is taken (at least one test)
+    fn take_half(tests: &mut VecDeque<(String, f64)>, from_back: bool) -> Vec<(String, f64)> {
+        let half = Self::remaining(tests) / 2.0;
+        let mut taken = vec![];
+        let mut time = 0.0;
+        while time < half || taken.is_empty() {
+            let test = if from_back {
+                tests.pop_back()
+            } else {
+                tests.pop_front()
+            };
+            let Some(test) = test else { break };
+            time += test.1;
+            taken.push(test);
+        }
+        if from_back {
+            taken.reverse();
+        }
+        taken
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(batch: Vec<(String, f64)>) -> Vec<String> {
+        batch.into_iter().map(|(path, _)| path).collect()
+    }
+
+    #[test]
+    fn should_steal_work_from_busiest_node() {
+        let mut timings = TimingHistory::default();
+        for (path, time) in [("a", 8.0), ("b", 4.0), ("c", 2.0), ("d", 1.0), ("e", 1.0)] {
+            timings.record(path, time);
+        }
+        let paths = ["a", "b", "c", "d", "e"].map(String::from);
+        let mut schedule = Schedule::new(&timings, &paths, 2);
+
+        // Node 0 gets the longest test, node 1 all the others
+        assert_eq!(names(schedule.next_batch(0)), vec!["a"]);
+        assert_eq!(names(schedule.next_batch(1)), vec!["b"]);
+        // Node 0 runs out of work and steals shortest tests from node 1
+        assert_eq!(names(schedule.next_batch(0)), vec!["d", "e"]);
+        assert_eq!(names(schedule.next_batch(1)), vec!["c"]);
+        assert!(schedule.next_batch(0).is_empty());
+        assert!(schedule.next_batch(1).is_empty());
+    }
+
+    #[test]
+    fn should_drop_schedules_of_idle_builds() -> anyhow::Result<()> {
+        let storage = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let mut coordinator = Coordinator::new(&storage)?.with_schedule_ttl(Duration::ZERO);
+        let request = |build_id: &str| {
+            json!({
+                "build_id": build_id,
+                "node_total": 1,
+                "node_index": 0,
+                "test_files": [{ "path": "pn|bn|a" }],
+            })
+            .to_string()
+        };
+
+        coordinator.handle(
+            "POST",
+            "/v1/coordinator/batch",
+            None,
+            request("first").as_bytes(),
+        );
+        assert!(coordinator.schedules.contains_key("first"));
+        std::thread::sleep(Duration::from_millis(10));
+        coordinator.handle(
+            "POST",
+            "/v1/coordinator/batch",
+            None,
+            request("second").as_bytes(),
+        );
+        assert!(!coordinator.schedules.contains_key("first"));
+        assert!(coordinator.schedules.contains_key("second"));
+        Ok(())
+    }
+}
@@ -0,0 +1,253 @@
+use crate::knapsack_client::api::{BuildSubsetRequest, QueueRequest, SubsetRequest, TestFile};
+use crate::server::queue::{QueueResponse, Queues};
+use crate::server::{parse_body, Handler, HttpResponse};
+use crate::timings::{partition_longest_first, TimingHistory};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Longer than any build, queues of finished or abandoned builds don't pile up
+const DEFAULT_QUEUE_TTL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Stand-in for Knapsack Pro API. Every token gets its own queues, so several test suites can
+/// be run against it. Timings uploaded to it are kept in the storage directory and used to
+/// order queues and split tests in Regular Mode.
+pub struct KnapsackApi {
+    timings_path: PathBuf,
+    timings: TimingHistory,
+    // By test suite token
+    queues: HashMap<String, Queues>,
+    queue_ttl: Duration,
+}
+
+impl KnapsackApi {
+    /// Server keeping its data in `storage`
+    pub fn new(storage: &Path) -> anyhow::Result<Self> {
+        let timings_path = storage.join("timings.json");
+        let timings = TimingHistory::load(&timings_path)?;
+        Ok(Self {
+            timings_path,
+            timings,
+            queues: HashMap::new(),
+            queue_ttl: DEFAULT_QUEUE_TTL,
+        })
+    }
+
+    /// Queue of a build is dropped when none of its nodes asked for tests for this long
+    pub fn with_queue_ttl(mut self, queue_ttl: Duration) -> Self {
+        self.queue_ttl = queue_ttl;
+        self
+    }
+
+    fn queue(&mut self, token: &str, body: &[u8]) -> Result<HttpResponse, HttpResponse> {
+        let request = parse_body::<QueueRequest>(body)?;
+        let queue_ttl = self.queue_ttl;
+        self.queues.retain(|_, queues| queues.drop_idle(queue_ttl));
+        let response = self
+            .queues
+            .entry(token.to_string())
+            .or_default()
+            .handle(request, &self.timings)
+            .map_err(|e| HttpResponse::error(422, e))?;
+
+        Ok(match response {
+            QueueResponse::AttemptConnectToQueueFailed => HttpResponse::ok(json!({
+                "queue_name": "",
+                "message": "Attempt to connect to queue failed",
+                "code": "ATTEMPT_CONNECT_TO_QUEUE_FAILED"
+            })),
+            QueueResponse::Batch(test_files) => HttpResponse::ok(json!({
+                "queue_name": "",
+                "test_files": test_files
+            })),
+        })
+    }
+
+    fn build_subsets(&mut self, body: &[u8]) -> Result<HttpResponse, HttpResponse> {
+        let request = parse_body::<BuildSubsetRequest>(body)?;
+        for file in request.test_files {
+            let time = file.time_execution.ok_or_else(|| {
+                HttpResponse::error(422, format!("Missing time_execution for [{}]", file.path))
+            })?;
+            self.timings.record(&file.path, time);
+        }
+        self.timings
+            .save(&self.timings_path)
+            .map_err(|e| HttpResponse::error(500, format!("{e:#}")))?;
+        Ok(HttpResponse::ok(json!({})))
+    }
+
+    fn build_distribution_subset(&self, body: &[u8]) -> Result<HttpResponse, HttpResponse> {
+        let request = parse_body::<SubsetRequest>(body)?;
+        if request.node_index >= request.node_total {
+            return Err(HttpResponse::error(
+                422,
+                format!(
+                    "Node index {} is out of range for {} nodes",
+                    request.node_index, request.node_total
+                ),
+            ));
+        }
+
+        let paths = request
+            .test_files
+            .into_iter()
+            .map(|file| file.path)
+            .collect::<Vec<_>>();
+        let mut partition =
+            partition_longest_first(self.timings.longest_first(&paths), request.node_total);
+        let test_files = partition
+            .swap_remove(request.node_index)
+            .into_iter()
+            .map(|(path, time)| TestFile {
+                path,
+                time_execution: Some(time),
+            })
+            .collect::<Vec<_>>();
+
+        Ok(HttpResponse::ok(json!({
+            "node_index": request.node_index,
+            "test_files": test_files
+        })))
+    }
+}
+
+impl Handler for KnapsackApi {
+    fn handle(
+        &mut self,
+        method: &str,
+        path: &str,
+        token: Option<&str>,
+        body: &[u8],
+    ) -> HttpResponse {
+        // Any token is accepted, but clients must send one
+        let token = match token {
+            Some(token) if !token.is_empty() => token,
+            _ => return HttpResponse::error(403, "Missing test suite token"),
+        };
+        let response = match (method, path) {
+            ("POST", "/v1/queues/queue") => self.queue(token, body),
+            ("POST", "/v1/build_subsets") => self.build_subsets(body),
+            ("POST", "/v1/build_distributions/subset") => self.build_distribution_subset(body),
+            // Splits of previous builds are not kept, only used to check the token
+            ("GET", "/v1/build_distributions/last") => Ok(HttpResponse::ok(json!({
+                "test_files": []
+            }))),
+            _ => Err(HttpResponse::error(
+                404,
+                format!("Unknown endpoint [{method} {path}]"),
+            )),
+        };
+        response.unwrap_or_else(|e| e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ci_providers::ci_provider_wrapper::CiProviderWrapper;
+    use crate::knapsack_client::token::TestSuiteToken;
+    use crate::knapsack_client::{KnapsackClient, KnapsackMode};
+    use crate::models::{Test, TestOutcome, TestResult};
+    use crate::queue_backend::QueueBackend;
+    use crate::server::HttpServer;
+    use crate::test_context::TestContext;
+    use crate::test_utils::{FixedTests, TestNode};
+
+    fn client<'a>(server: &str, tests: &'a FixedTests, node_index: usize) -> KnapsackClient<'a> {
+        suite_client(server, "token", tests, node_index)
+    }
+
+    fn suite_client<'a>(
+        server: &str,
+        token: &str,
+        tests: &'a FixedTests,
+        node_index: usize,
+    ) -> KnapsackClient<'a> {
+        KnapsackClient::new(
+            server.into(),
+            TestSuiteToken::new(token).unwrap(),
+            tests,
+            CiProviderWrapper::new(Box::new(TestNode::new(node_index, 2))),
+        )
+    }
+
+    fn drain(client: &mut KnapsackClient) -> anyhow::Result<Vec<Test>> {
+        let mut tests = vec![];
+        loop {
+            let mut batch = client.get_tests()?;
+            if batch.is_empty() {
+                return Ok(tests);
+            }
+            tests.append(&mut batch);
+        }
+    }
+
+    #[test]
+    fn should_split_tests_between_nodes() -> anyhow::Result<()> {
+        let storage = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let server = HttpServer::bind("127.0.0.1:0", KnapsackApi::new(&storage)?)?.spawn()?;
+        let tests = FixedTests::new(&["a", "b", "c", "d"]);
+
+        let mut node_0 = client(server.base_url(), &tests, 0);
+        let mut node_1 = client(server.base_url(), &tests, 1);
+        node_0.validate_token()?;
+        let mut all_tests = node_0.get_tests()?;
+        all_tests.append(&mut drain(&mut node_1)?);
+        all_tests.append(&mut drain(&mut node_0)?);
+        all_tests.sort();
+        assert_eq!(all_tests, tests.find_tests()?);
+
+        let results = tests
+            .find_tests()?
+            .into_iter()
+            .map(|test| TestResult {
+                exec_time: if test.test_name == "d" { 10.0 } else { 1.0 },
+                attempt: 1,
+                outcome: TestOutcome::Passed,
+                test,
+                finished_at: None,
+            })
+            .collect::<Vec<_>>();
+        node_0.upload_test_results(&results)?;
+        assert_eq!(
+            TimingHistory::load(&storage.join("timings.json"))?.get("pn|bn|d"),
+            Some(10.0)
+        );
+
+        // Longest test goes to the first node, remaining ones fill the other one
+        let subset = |node_index| {
+            client(server.base_url(), &tests, node_index)
+                .with_mode(KnapsackMode::Regular)
+                .get_tests()
+        };
+        assert_eq!(subset(0)?, vec![tests.find_tests()?[3].clone()]);
+        assert_eq!(subset(1)?.len(), 3);
+
+        std::fs::remove_dir_all(storage)?;
+        Ok(())
+    }
+
+    #[test]
+    fn should_keep_separate_queue_per_token() -> anyhow::Result<()> {
+        let storage = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let server = HttpServer::bind("127.0.0.1:0", KnapsackApi::new(&storage)?)?.spawn()?;
+        let unit_tests = FixedTests::new(&["a", "b"]);
+        let integration_tests = FixedTests::new(&["c"]);
+
+        let unit = drain(&mut suite_client(server.base_url(), "unit", &unit_tests, 0))?;
+        let integration = drain(&mut suite_client(
+            server.base_url(),
+            "integration",
+            &integration_tests,
+            0,
+        ))?;
+
+        assert_eq!(unit, unit_tests.find_tests()?);
+        assert_eq!(integration, integration_tests.find_tests()?);
+
+        std::fs::remove_dir_all(storage).ok();
+        Ok(())
+    }
+}
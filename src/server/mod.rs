@@ -0,0 +1,168 @@
+use anyhow::{anyhow, Context};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use tiny_http::{Header, Response, Server};
+use tracing::warn;
+
+/// Self-hosted queue used by the `coordinator` subcommand
+pub mod coordinator;
+/// Subset of the Knapsack Pro API used by the `serve` subcommand
+pub mod knapsack_api;
+pub(crate) mod queue;
+
+/// JSON response of a handler
+pub struct HttpResponse {
+    /// HTTP status code
+    pub status: u16,
+    /// JSON body
+    pub body: Value,
+}
+
+impl HttpResponse {
+    /// Response with status 200
+    pub fn ok(body: Value) -> Self {
+        Self { status: 200, body }
+    }
+
+    /// Response with given status and an error message in Knapsack Pro format
+    pub fn error(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            body: json!({ "errors": [message.into()] }),
+        }
+    }
+}
+
+/// Requests are handled one by one, so handlers don't need any synchronization
+pub trait Handler: Send + 'static {
+    /// Handles a request, `token` is value of the `KNAPSACK-PRO-TEST-SUITE-TOKEN` header
+    fn handle(
+        &mut self,
+        method: &str,
+        path: &str,
+        token: Option<&str>,
+        body: &[u8],
+    ) -> HttpResponse;
+}
+
+/// HTTP server passing requests to a handler
+pub struct HttpServer<H: Handler> {
+    server: Arc<Server>,
+    handler: H,
+}
+
+/// Server running on background thread, stopped when dropped
+pub struct RunningServer {
+    server: Arc<Server>,
+    base_url: String,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<H: Handler> HttpServer<H> {
+    /// Listens on `address`, port 0 picks a free port
+    pub fn bind(address: &str, handler: H) -> anyhow::Result<Self> {
+        let server =
+            Server::http(address).map_err(|e| anyhow!("Failed to listen on [{address}]: [{e}]"))?;
+        Ok(Self {
+            server: Arc::new(server),
+            handler,
+        })
+    }
+
+    /// Address the server listens on
+    pub fn address(&self) -> anyhow::Result<SocketAddr> {
+        self.server
+            .server_addr()
+            .to_ip()
+            .context("Server is not listening on IP address")
+    }
+
+    /// URL of the server, e.g. `http://127.0.0.1:8080`
+    pub fn base_url(&self) -> anyhow::Result<String> {
+        Ok(format!("http://{}", self.address()?))
+    }
+
+    /// Handles requests until the process is stopped
+    pub fn serve(mut self) -> anyhow::Result<()> {
+        loop {
+            let request = match self.server.recv() {
+                Ok(request) => request,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context("Failed to receive request"),
+            };
+            self.respond(request);
+        }
+    }
+
+    /// Handles requests on a background thread
+    pub fn spawn(mut self) -> anyhow::Result<RunningServer> {
+        let base_url = self.base_url()?;
+        let server = self.server.clone();
+        let thread = std::thread::spawn(move || {
+            // Stops when server is unblocked
+            while let Ok(request) = self.server.recv() {
+                self.respond(request);
+            }
+        });
+        Ok(RunningServer {
+            server,
+            base_url,
+            thread: Some(thread),
+        })
+    }
+
+    fn respond(&mut self, mut request: tiny_http::Request) {
+        let mut body = vec![];
+        let response = match request.as_reader().read_to_end(&mut body) {
+            Ok(_) => {
+                let token = request
+                    .headers()
+                    .iter()
+                    .find(|header| header.field.equiv("KNAPSACK-PRO-TEST-SUITE-TOKEN"))
+                    .map(|header| header.value.to_string());
+                let path = request
+                    .url()
+                    .split('?')
+                    .next()
+                    .unwrap_or_default()
+                    .to_string();
+                self.handler
+                    .handle(request.method().as_str(), &path, token.as_deref(), &body)
+            }
+            Err(e) => HttpResponse::error(400, format!("Failed to read request: [{e}]")),
+        };
+
+        let response = Response::from_string(response.body.to_string())
+            .with_status_code(response.status)
+            .with_header(
+                Header::from_bytes("Content-Type", "application/json")
+                    .expect("Content-Type header is valid"),
+            );
+        if let Err(e) = request.respond(response) {
+            warn!("Failed to send response: [{e}]");
+        }
+    }
+}
+
+impl RunningServer {
+    /// URL of the server, e.g. `http://127.0.0.1:8080`
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+}
+
+impl Drop for RunningServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+pub(crate) fn parse_body<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T, HttpResponse> {
+    serde_json::from_slice(body)
+        .map_err(|e| HttpResponse::error(422, format!("Invalid request body: [{e}]")))
+}
@@ -0,0 +1,259 @@
+use crate::knapsack_client::api::{QueueRequest, TestFile};
+use crate::timings::{queue_batches, TimingHistory};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum QueueResponse {
+    AttemptConnectToQueueFailed,
+    Batch(Vec<TestFile>),
+}
+
+// With fixed queue split, build id is not a part of the key: every build of the same
+// commit, branch and node total gets the same split, so retried nodes run the same tests
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct QueueKey {
+    commit_hash: String,
+    branch: String,
+    node_total: usize,
+    node_build_id: Option<String>,
+}
+
+struct Queue {
+    fixed_queue_split: bool,
+    remaining: VecDeque<Vec<TestFile>>,
+    // Batches handed out to every node, replayed for fixed queue split
+    batches: HashMap<usize, Vec<Vec<TestFile>>>,
+    cursors: HashMap<usize, usize>,
+    last_request: Instant,
+}
+
+#[derive(Default)]
+pub(crate) struct Queues {
+    queues: HashMap<QueueKey, Queue>,
+}
+
+impl Queues {
+    // Drops queues none of whose nodes asked for tests for `ttl`, tells whether any is left
+    pub(crate) fn drop_idle(&mut self, ttl: Duration) -> bool {
+        self.queues
+            .retain(|_, queue| queue.last_request.elapsed() <= ttl);
+        !self.queues.is_empty()
+    }
+
+    pub(crate) fn handle(
+        &mut self,
+        request: QueueRequest,
+        timings: &TimingHistory,
+    ) -> Result<QueueResponse, String> {
+        if request.node_index >= request.node_total {
+            return Err(format!(
+                "Node index {} is out of range for {} nodes",
+                request.node_index, request.node_total
+            ));
+        }
+
+        let key = QueueKey {
+            commit_hash: request.commit_hash,
+            branch: request.branch,
+            node_total: request.node_total,
+            node_build_id: (!request.fixed_queue_split).then_some(request.node_build_id),
+        };
+        let node_index = request.node_index;
+
+        let queue = match (
+            request.can_initialize_queue,
+            request.attempt_connect_to_queue,
+        ) {
+            (true, true) => match self.queues.get_mut(&key) {
+                Some(queue) => {
+                    queue.restart(node_index);
+                    queue
+                }
+                None => return Ok(QueueResponse::AttemptConnectToQueueFailed),
+            },
+            (true, false) => {
+                let test_files = request
+                    .test_files
+                    .ok_or("test_files are required to initialize queue")?;
+                let queue = self.queues.entry(key).or_insert_with(|| {
+                    Queue::new(
+                        request.fixed_queue_split,
+                        request.node_total,
+                        &test_files,
+                        timings,
+                    )
+                });
+                queue.restart(node_index);
+                queue
+            }
+            (false, _) => self
+                .queues
+                .get_mut(&key)
+                .ok_or("Queue was not initialized")?,
+        };
+
+        queue.last_request = Instant::now();
+        Ok(QueueResponse::Batch(queue.next_batch(node_index)))
+    }
+}
+
+impl Queue {
+    fn new(
+        fixed_queue_split: bool,
+        node_total: usize,
+        test_files: &[TestFile],
+        timings: &TimingHistory,
+    ) -> Self {
+        let paths = test_files
+            .iter()
+            .map(|file| file.path.clone())
+            .collect::<Vec<_>>();
+        let remaining = queue_batches(timings.longest_first(&paths), node_total)
+            .into_iter()
+            .map(|batch| {
+                batch
+                    .into_iter()
+                    .map(|(path, time)| TestFile {
+                        path,
+                        time_execution: Some(time),
+                    })
+                    .collect()
+            })
+            .collect();
+        Self {
+            fixed_queue_split,
+            remaining,
+            batches: HashMap::new(),
+            cursors: HashMap::new(),
+            last_request: Instant::now(),
+        }
+    }
+
+    // Node connected to the queue again, with fixed split it gets its previous batches first
+    fn restart(&mut self, node_index: usize) {
+        if self.fixed_queue_split {
+            self.cursors.insert(node_index, 0);
+        }
+    }
+
+    fn next_batch(&mut self, node_index: usize) -> Vec<TestFile> {
+        let cursor = self.cursors.entry(node_index).or_default();
+        let batches = self.batches.entry(node_index).or_default();
+
+        if let Some(batch) = batches.get(*cursor) {
+            *cursor += 1;
+            return batch.clone();
+        }
+
+        match self.remaining.pop_front() {
+            Some(batch) => {
+                batches.push(batch.clone());
+                *cursor = batches.len();
+                batch
+            }
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(
+        can_initialize_queue: bool,
+        attempt_connect_to_queue: bool,
+        node_index: usize,
+    ) -> QueueRequest {
+        QueueRequest {
+            can_initialize_queue,
+            attempt_connect_to_queue,
+            fixed_queue_split: true,
+            commit_hash: "commit_hash".into(),
+            branch: "branch".into(),
+            node_total: 2,
+            node_index,
+            node_build_id: "build_id".into(),
+            test_files: (can_initialize_queue && !attempt_connect_to_queue).then(|| {
+                ["a", "b", "c", "d"]
+                    .map(|path| TestFile {
+                        path: path.into(),
+                        time_execution: None,
+                    })
+                    .to_vec()
+            }),
+        }
+    }
+
+    fn paths(response: QueueResponse) -> Vec<String> {
+        match response {
+            QueueResponse::Batch(batch) => batch.into_iter().map(|file| file.path).collect(),
+            QueueResponse::AttemptConnectToQueueFailed => panic!("Expected batch"),
+        }
+    }
+
+    #[test]
+    fn should_drain_queue_longest_first() {
+        let mut timings = TimingHistory::default();
+        timings.record("a", 1.0);
+        timings.record("b", 2.0);
+        timings.record("c", 3.0);
+        timings.record("d", 6.0);
+        let mut queues = Queues::default();
+
+        assert_eq!(
+            queues.handle(request(true, true, 0), &timings),
+            Ok(QueueResponse::AttemptConnectToQueueFailed)
+        );
+        assert_eq!(
+            paths(queues.handle(request(true, false, 0), &timings).unwrap()),
+            vec!["d"]
+        );
+        assert_eq!(
+            paths(queues.handle(request(true, true, 1), &timings).unwrap()),
+            vec!["c"]
+        );
+        assert_eq!(
+            paths(queues.handle(request(false, false, 0), &timings).unwrap()),
+            vec!["b"]
+        );
+        assert_eq!(
+            paths(queues.handle(request(false, false, 1), &timings).unwrap()),
+            vec!["a"]
+        );
+        assert!(paths(queues.handle(request(false, false, 0), &timings).unwrap()).is_empty());
+
+        // Retried node gets the same tests with fixed queue split
+        assert_eq!(
+            paths(queues.handle(request(true, true, 1), &timings).unwrap()),
+            vec!["c"]
+        );
+        assert_eq!(
+            paths(queues.handle(request(false, false, 1), &timings).unwrap()),
+            vec!["a"]
+        );
+        assert!(paths(queues.handle(request(false, false, 1), &timings).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn should_drop_idle_queues() {
+        let timings = TimingHistory::default();
+        let mut queues = Queues::default();
+        queues.handle(request(true, false, 0), &timings).unwrap();
+
+        assert!(queues.drop_idle(Duration::from_secs(60)));
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(!queues.drop_idle(Duration::ZERO));
+        assert!(queues.handle(request(false, false, 1), &timings).is_err());
+    }
+
+    #[test]
+    fn should_not_get_batch_from_uninitialized_queue() {
+        let mut queues = Queues::default();
+
+        assert!(queues
+            .handle(request(false, false, 0), &TimingHistory::default())
+            .is_err());
+    }
+}
@@ -0,0 +1,57 @@
+use anyhow::Context;
+use std::process::Child;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Exit code used when run was stopped by SIGINT/SIGTERM (128 + SIGINT, same as shells use)
+pub const INTERRUPTED_EXIT_CODE: u8 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Installs Ctrl+C/SIGTERM handler, processes stop after their current batch
+pub fn install_handler() -> anyhow::Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            warn!("Termination already requested, waiting for tests to stop");
+        } else {
+            warn!("Termination requested, stopping tests");
+        }
+    })
+    .context("Failed to install termination signal handler")
+}
+
+/// Whether termination was requested
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Forwards termination to the child and gives it `timeout` to finish before killing it
+pub fn stop_child(child: &mut Child, timeout: Duration) -> anyhow::Result<()> {
+    forward_termination(child);
+
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if child.try_wait().context("Failed to get status")?.is_some() {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    warn!("Tests did not stop within {:?}, killing them", timeout);
+    child.kill().context("Failed to kill cargo nextest")?;
+    child.wait().context("Failed to get status")?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn forward_termination(child: &Child) {
+    // cargo replaces itself with cargo-nextest, so this pid is nextest itself
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+}
+
+// On Windows console control events are delivered to the whole process group already
+#[cfg(not(unix))]
+fn forward_termination(_child: &Child) {}
@@ -1,23 +1,150 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::fs::File;
+use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
 use anyhow::Context;
-use nextest_metadata::ListCommand;
+use nextest_metadata::{ListCommand, NextestExitCode};
 use serde_json::Value;
-use crate::models::{Test, TestResult};
+use tracing::{debug, info, warn};
+use crate::doctests;
+use crate::doctests::DOCTEST_BINARY_NAME;
+use crate::failure::{Classify, Failure};
+use crate::models::{Test, TestOutcome, TestResult};
+use crate::shutdown;
+
+/// Directory (relative to the workspace root) where nextest-knapsack keeps its files
+pub const KNAPSACK_DIRECTORY: &str = "target/nextest-knapsack";
+
+// Longest filterset passed to a single nextest invocation, keeps command line well below
+// limits of all platforms (32 KiB on Windows)
+const MAX_FILTERSET_LENGTH: usize = 16 * 1024;
+
+// Line of output of a command and when it was received
+type OutputLine = (Instant, String);
+
+/// Default time given to nextest to stop gracefully after termination was requested
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Which tests are run with respect to `#[ignore]`, same as nextest `--run-ignored`
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum RunIgnored {
+    /// Run only tests that are not ignored
+    #[default]
+    Default,
+    /// Run only ignored tests
+    Only,
+    /// Run both ignored and not ignored tests
+    All,
+}
+
+impl RunIgnored {
+    fn nextest_arg(self) -> &'static str {
+        match self {
+            RunIgnored::Default => "default",
+            RunIgnored::Only => "only",
+            RunIgnored::All => "all",
+        }
+    }
+}
+
+/// Subset of workspace tests that is distributed between nodes
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TestSelection {
+    /// Packages to take tests from, all workspace packages when empty
+    pub packages: Vec<String>,
+    /// Workspace packages to skip, used only when `packages` is empty
+    pub exclude_packages: Vec<String>,
+    /// Only tests matching any of these filtersets are selected, all tests when empty
+    pub filtersets: Vec<String>,
+    /// Tests matching any of these filtersets are not selected
+    pub exclude_filtersets: Vec<String>,
+    /// Whether doctests of selected packages are selected, filtersets don't apply to them
+    pub doctests: bool,
+}
+
+impl TestSelection {
+    /// Selection narrowed to tests that also match `filterset`, e.g. tests of one test suite
+    pub fn narrowed_to(&self, filterset: &str) -> Self {
+        let filtersets = if self.filtersets.is_empty() {
+            vec![filterset.to_string()]
+        } else {
+            self.filtersets
+                .iter()
+                .map(|selected| format!("({filterset}) & ({selected})"))
+                .collect()
+        };
+        Self {
+            filtersets,
+            ..self.clone()
+        }
+    }
+
+    // Arguments selecting packages for `cargo nextest list`
+    fn package_args(&self) -> Vec<&str> {
+        if self.packages.is_empty() {
+            let mut args = vec!["--workspace"];
+            for package in &self.exclude_packages {
+                args.extend(["--exclude", package]);
+            }
+            args
+        } else {
+            self.packages
+                .iter()
+                .flat_map(|package| ["--package", package])
+                .collect()
+        }
+    }
+
+    // Single filterset combining included and excluded filtersets, none if all tests are selected
+    fn filterset(&self) -> Option<String> {
+        let any = |filtersets: &[String]| {
+            filtersets
+                .iter()
+                .map(|filterset| format!("({filterset})"))
+                .collect::<Vec<_>>()
+                .join(" | ")
+        };
+        match (
+            self.filtersets.is_empty(),
+            self.exclude_filtersets.is_empty(),
+        ) {
+            (true, true) => None,
+            (false, true) => Some(any(&self.filtersets)),
+            (true, false) => Some(format!("not ({})", any(&self.exclude_filtersets))),
+            (false, false) => Some(format!(
+                "({}) & not ({})",
+                any(&self.filtersets),
+                any(&self.exclude_filtersets)
+            )),
+        }
+    }
+}
 
-pub(crate) trait TestContext {
+/// Discovery and running of tests, implemented with nextest by [`DefaultTestContext`]
+pub trait TestContext {
+    /// All selected tests of the workspace
     fn find_tests(&self) -> anyhow::Result<Vec<Test>>;
-    fn run_tests(&self, tests: &Vec<Test>) -> anyhow::Result<Vec<TestResult>>;
+    /// Runs given tests, returns results of the tests that passed, failed or timed out.
+    /// Failing tests are not an error.
+    fn run_tests(&self, tests: &[Test]) -> anyhow::Result<Vec<TestResult>>;
 }
 
 
-pub(crate) struct DefaultTestContext {
+/// Runs tests with `cargo nextest`, discovery metadata is removed when dropped
+pub struct DefaultTestContext {
     directory: PathBuf,
+    metadata_directory: PathBuf,
     cargo_metadata_path: PathBuf,
     binaries_metadata_path: PathBuf,
+    shutdown_timeout: Duration,
+    run_ignored: RunIgnored,
+    nextest_profile: Option<String>,
+    retries: Option<u32>,
+    selection_filterset: Option<String>,
+    doctest_packages: Vec<String>,
 }
 
 impl TestContext for DefaultTestContext {
@@ -25,15 +152,28 @@ impl TestContext for DefaultTestContext {
         let mut command = ListCommand::new();
         command.add_arg("--binaries-metadata").add_arg(self.binaries_metadata_path.to_str().unwrap());
         command.add_arg("--cargo-metadata").add_arg(self.cargo_metadata_path.to_str().unwrap());
+        command.add_arg("--run-ignored").add_arg(self.run_ignored.nextest_arg());
+        if let Some(profile) = &self.nextest_profile {
+            command.add_arg("--profile").add_arg(profile);
+        }
+        if let Some(filterset) = &self.selection_filterset {
+            command.add_arg("-E").add_arg(filterset);
+        }
         command.current_dir(self.directory.to_str().unwrap().to_string());
         let test_list = command
             .exec()
-            .with_context(|| format!("Failed to list tests in directory [{}]", self.directory.to_str().unwrap()))?;
+            .with_context(|| format!("Failed to list tests in directory [{}]", self.directory.to_str().unwrap()))
+            .classify(Failure::BuildFailed)?;
 
         let mut tests = Vec::new();
 
         for (_, suite) in test_list.rust_suites {
-            for (test_name, _) in suite.test_cases {
+            for (test_name, test_case) in suite.test_cases {
+                // Skips ignored tests (depending on --run-ignored) and tests excluded by
+                // default-filter of nextest profile, they would never be run
+                if !test_case.filter_match.is_match() {
+                    continue;
+                }
                 tests.push(Test {
                     package_name: suite.package_name.clone(),
                     binary_name: suite.binary.binary_name.clone(),
@@ -41,95 +181,127 @@ impl TestContext for DefaultTestContext {
                 });
             }
         }
-        println!("Found {} tests.", tests.len());
+        for package in &self.doctest_packages {
+            let mut doctests = doctests::find_doctests(&self.directory, package, self.run_ignored)?;
+            tests.append(&mut doctests);
+        }
+        info!("Found {} tests", tests.len());
 
         Ok(tests)
 
     }
 
-    fn run_tests(&self, tests: &Vec<Test>) -> anyhow::Result<Vec<TestResult>> {
-        let nextest_names_map = tests
+    fn run_tests(&self, tests: &[Test]) -> anyhow::Result<Vec<TestResult>> {
+        let (doctests, tests_to_run) = tests
             .iter()
-            .map(|test| (test.to_nextest_name(), test))
-            .collect::<HashMap<_, _>>();
-
-        let args = tests
+            .partition::<Vec<_>, _>(|test| test.binary_name == DOCTEST_BINARY_NAME);
+        let nextest_names_map = tests_to_run
             .iter()
-            .map(|t| t.to_nextest_filter())
-            .flatten()
-            .collect::<Vec<_>>();
-
-        let mut command = Command::new("cargo");
-        command
-            .current_dir(&self.directory)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::inherit())
-            .env("NEXTEST_EXPERIMENTAL_LIBTEST_JSON", "1")
-            .args(&[
-                "nextest",
-                "run",
-                "--message-format",
-                "libtest-json",
-                "--binaries-metadata",
-                self.binaries_metadata_path.to_str().unwrap(),
-                "--cargo-metadata",
-                self.cargo_metadata_path.to_str().unwrap(),
-            ])
-            .args(&args);
-
-        let mut spawn = command.spawn().context("Failed to spawn cargo nextest")?;
-
-        let status = spawn.wait().context("Failed to get status")?;
-        if !status.success() {
-            anyhow::bail!("Failed to run tests: {}", status);
-        }
+            .map(|test| (test.to_nextest_name(), *test))
+            .collect::<HashMap<_, _>>();
+        let tests_to_run = tests_to_run.into_iter().cloned().collect::<Vec<_>>();
 
+        // Huge batches are run by several nextest invocations
         let mut test_results = Vec::new();
-
-        let stdout = spawn.wait_with_output()?.stdout;
-
-        for line in String::from_utf8_lossy(&stdout).lines() {
-            let v: Value =
-                serde_json::from_str(line).with_context(|| format!("Cannot parse JSON: {}", line))?;
-
-            if v.get("type").unwrap() == "test" && v.get("event").unwrap() == "ok" {
-                let name = v.get("name").unwrap().as_str().unwrap().to_string();
-                let exec_time = v.get("exec_time").unwrap().as_f64().unwrap();
-
-                let test = nextest_names_map
-                    .get(&name)
-                    .with_context(|| format!("Unknown test: {}", name))?;
-
-                test_results.push(TestResult {
-                    test: (*test).clone(),
-                    exec_time,
-                });
+        for filterset in batch_filtersets(&tests_to_run, self.selection_filterset.as_deref()) {
+            if shutdown::is_interrupted() {
+                break;
             }
+            test_results.append(&mut self.run_filterset(&filterset, &nextest_names_map)?);
+        }
+        test_results.append(&mut self.run_doctests(&doctests)?);
+
+        if test_results.len() < tests.len() && !shutdown::is_interrupted() {
+            warn!(
+                "{} tests of the batch were not run, they don't match test selection",
+                tests.len() - test_results.len()
+            );
         }
 
         Ok(test_results)
     }
 }
 
+// Filtersets of nextest invocations running the tests. Tests from the queue that are not selected
+// anymore are never run, so every filterset is restricted to the selection and is no longer than
+// MAX_FILTERSET_LENGTH together with it.
+fn batch_filtersets(tests: &[Test], selection: Option<&str>) -> Vec<String> {
+    let Some(selection) = selection else {
+        return Test::to_nextest_filtersets(tests, MAX_FILTERSET_LENGTH);
+    };
+    let max_length = MAX_FILTERSET_LENGTH.saturating_sub(selection.len() + "() & ()".len());
+    Test::to_nextest_filtersets(tests, max_length)
+        .into_iter()
+        .map(|filterset| format!("({filterset}) & ({selection})"))
+        .collect()
+}
+
 impl DefaultTestContext {
-    pub(crate) fn new(directory: &Path) -> anyhow::Result<Self> {
-        let cargo_metadata_path = Self::prepare_cargo_metadata(directory)?;
-        let binaries_metadata_path = Self::prepare_binaries_metadata(directory)?;
+    /// Builds the workspace in `directory` and lists its tests
+    pub fn new(directory: &Path, selection: &TestSelection) -> anyhow::Result<Self> {
+        // Every process gets its own directory, so nodes sharing a checkout don't clobber each other
+        let metadata_directory =
+            Path::new(KNAPSACK_DIRECTORY).join(format!("metadata-{}", std::process::id()));
+        fs::create_dir_all(directory.join(&metadata_directory))
+            .context("failed to create directory for nextest-knapsack")?;
+        let cargo_metadata_path = Self::prepare_cargo_metadata(directory, &metadata_directory)?;
+        let binaries_metadata_path =
+            Self::prepare_binaries_metadata(directory, &metadata_directory, selection)?;
+        let doctest_packages = if selection.doctests {
+            doctests::doctest_packages(&directory.join(&cargo_metadata_path), selection)?
+        } else {
+            vec![]
+        };
         Ok(Self {
             directory: directory.to_path_buf(),
+            metadata_directory,
             cargo_metadata_path,
-            binaries_metadata_path
+            binaries_metadata_path,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            run_ignored: RunIgnored::Default,
+            nextest_profile: None,
+            retries: None,
+            selection_filterset: selection.filterset(),
+            doctest_packages,
         })
     }
 
-    fn prepare_binaries_metadata(directory: &Path) -> anyhow::Result<PathBuf> {
-        let file_name = directory.join("target/nextest-knapsack/binaries-metadata.json");
-        fs::create_dir_all(directory.join("target/nextest-knapsack")).context("failed to create directory for nextest-knapsack")?;
-        let file = File::create(&file_name)
+    /// Time given to nextest to stop gracefully after termination was requested
+    pub fn with_shutdown_timeout(mut self, shutdown_timeout: Duration) -> Self {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    /// Sets which tests are run with respect to `#[ignore]`
+    pub fn with_run_ignored(mut self, run_ignored: RunIgnored) -> Self {
+        self.run_ignored = run_ignored;
+        self
+    }
+
+    /// Sets nextest profile used to list and run tests, nextest picks one when not set
+    pub fn with_nextest_profile(mut self, nextest_profile: Option<String>) -> Self {
+        self.nextest_profile = nextest_profile;
+        self
+    }
+
+    /// Sets number of retries of failing tests, retries of nextest profile are used when not set
+    pub fn with_retries(mut self, retries: Option<u32>) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    fn prepare_binaries_metadata(
+        directory: &Path,
+        metadata_directory: &Path,
+        selection: &TestSelection,
+    ) -> anyhow::Result<PathBuf> {
+        let path = metadata_directory.join("binaries-metadata.json");
+        let file = File::create(directory.join(&path))
             .context("failed to open file")?;
 
         let mut cmd = Command::new("cargo")
-            .args("nextest list --workspace --list-type binaries-only --message-format json".split(" "))
+            .args("nextest list --list-type binaries-only --message-format json".split(" "))
+            .args(selection.package_args())
             .stdout(file)
             .current_dir(directory)
             .spawn()
@@ -138,20 +310,23 @@ impl DefaultTestContext {
         let exit_status = cmd.wait().context("failed to wait for cargo nextest")?;
 
         if !exit_status.success() {
-            anyhow::bail!("Failed to prepare binaries metadata");
+            return Err(command_error(
+                "Failed to prepare binaries metadata",
+                exit_status,
+                nextest_failure(exit_status),
+            ));
         }
 
-        Ok("target/nextest-knapsack/binaries-metadata.json".into())
+        Ok(path)
     }
 
-    fn prepare_cargo_metadata(directory: &Path) -> anyhow::Result<PathBuf> {
-        let file_name = directory.join("target/nextest-knapsack/cargo-metadata.json");
-        fs::create_dir_all(directory.join("target/nextest-knapsack")).context("failed to create directory for nextest-knapsack")?;
-        let file = File::create(&file_name)
+    fn prepare_cargo_metadata(directory: &Path, metadata_directory: &Path) -> anyhow::Result<PathBuf> {
+        let path = metadata_directory.join("cargo-metadata.json");
+        let file = File::create(directory.join(&path))
             .context("failed to open file")?;
 
         let mut cmd = Command::new("cargo")
-            .args(&["metadata", "--format-version", "1"])
+            .args(["metadata", "--format-version", "1"])
             .stdout(file)
             .current_dir(directory)
             .spawn()
@@ -160,10 +335,238 @@ impl DefaultTestContext {
         let exit_status = cmd.wait().context("failed to wait for cargo metadata")?;
 
         if !exit_status.success() {
-            anyhow::bail!("Failed to prepare cargo metadata");
+            return Err(command_error(
+                "Failed to prepare cargo metadata",
+                exit_status,
+                Some(Failure::BuildFailed),
+            ));
+        }
+
+        Ok(path)
+    }
+
+    fn run_filterset(
+        &self,
+        filterset: &str,
+        nextest_names_map: &HashMap<String, &Test>,
+    ) -> anyhow::Result<Vec<TestResult>> {
+        let mut command = Command::new("cargo");
+        // Tests of the batch skipped after a failure (fail-fast) would never be run, they are
+        // already taken from the queue
+        command
+            .current_dir(&self.directory)
+            .env("NEXTEST_EXPERIMENTAL_LIBTEST_JSON", "1")
+            .args([
+                "nextest",
+                "run",
+                "--no-fail-fast",
+                "--message-format",
+                "libtest-json",
+                "--binaries-metadata",
+                self.binaries_metadata_path.to_str().unwrap(),
+                "--cargo-metadata",
+                self.cargo_metadata_path.to_str().unwrap(),
+                "--run-ignored",
+                self.run_ignored.nextest_arg(),
+                "-E",
+                filterset,
+            ]);
+        if let Some(profile) = &self.nextest_profile {
+            command.args(["--profile", profile]);
+        }
+        if let Some(retries) = self.retries {
+            command.args(["--retries", &retries.to_string()]);
+        }
+
+        let (status, lines) = self.run_command(command)?;
+        // Failed tests are returned with passed ones, so their time is uploaded as well
+        let tests_failed = status.is_some_and(|status| status.code() == Some(NextestExitCode::TEST_RUN_FAILED));
+        if let Some(status) = status.filter(|status| !status.success() && !tests_failed) {
+            return Err(command_error("Failed to run tests", status, nextest_failure(status)));
+        }
+
+        let mut test_results = Vec::new();
+
+        for (received, line) in &lines {
+            let v: Value = match serde_json::from_str(line) {
+                Ok(v) => v,
+                // Output of interrupted run can end in the middle of a line
+                Err(_) if status.is_none() => continue,
+                Err(e) => return Err(e).with_context(|| format!("Cannot parse JSON: {}", line)),
+            };
+
+            if v.get("type").unwrap() != "test" {
+                continue;
+            }
+            let outcome = match v.get("event").unwrap().as_str() {
+                Some("ok") => TestOutcome::Passed,
+                // Time of a test stopped by `slow-timeout.terminate-after` is the timeout
+                Some("failed") if v.get("reason").and_then(Value::as_str) == Some("time limit exceeded") => {
+                    TestOutcome::TimedOut
+                }
+                Some("failed") => TestOutcome::Failed,
+                _ => continue,
+            };
+            let (name, attempt) = split_attempt(v.get("name").unwrap().as_str().unwrap());
+            let exec_time = v.get("exec_time").unwrap().as_f64().unwrap();
+
+            let test = nextest_names_map
+                .get(name)
+                .with_context(|| format!("Unknown test: {}", name))?;
+
+            test_results.push(TestResult {
+                test: (*test).clone(),
+                exec_time,
+                attempt,
+                outcome,
+                // Nextest reports the test as soon as it finishes
+                finished_at: Some(*received),
+            });
+        }
+
+        if tests_failed && test_results.iter().all(|result| result.outcome == TestOutcome::Passed) {
+            return Err(Failure::TestsFailed.error(anyhow::anyhow!(
+                "Failed to run tests, nextest reported a failure but no failed test"
+            )));
+        }
+
+        Ok(test_results)
+    }
+
+    fn run_doctests(&self, doctests: &[&Test]) -> anyhow::Result<Vec<TestResult>> {
+        let mut by_package = BTreeMap::<&str, Vec<&Test>>::new();
+        for test in doctests {
+            // Packages that are not selected anymore are never run
+            if self.doctest_packages.contains(&test.package_name) {
+                by_package.entry(&test.package_name).or_default().push(test);
+            }
+        }
+
+        let mut test_results = Vec::new();
+        for (package, tests) in by_package {
+            let mut chunks: Vec<Vec<&Test>> = vec![];
+            let mut length = 0;
+            for test in tests {
+                if chunks.is_empty() || length + test.test_name.len() > MAX_FILTERSET_LENGTH {
+                    chunks.push(vec![]);
+                    length = 0;
+                }
+                length += test.test_name.len() + 1;
+                chunks.last_mut().unwrap().push(test);
+            }
+
+            for chunk in chunks {
+                if shutdown::is_interrupted() {
+                    break;
+                }
+                let started = Instant::now();
+                let command =
+                    doctests::doctest_command(&self.directory, package, &chunk, self.run_ignored);
+                let (status, lines) = self.run_command(command)?;
+                let lines = lines.into_iter().map(|(_, line)| line).collect::<Vec<_>>();
+                let mut results = doctests::parse_doctest_results(
+                    &lines,
+                    &chunk,
+                    started.elapsed().as_secs_f64(),
+                );
+                // Doctest that fails to compile is reported as failed, the library failing to
+                // compile is not
+                let doctests_failed = results.iter().any(|result| result.outcome != TestOutcome::Passed);
+                if let Some(status) = status.filter(|status| !status.success() && !doctests_failed) {
+                    return Err(command_error(
+                        "Failed to run doctests",
+                        status,
+                        Some(Failure::BuildFailed),
+                    ));
+                }
+                test_results.append(&mut results);
+            }
+        }
+
+        Ok(test_results)
+    }
+
+    // Runs the command until it finishes or termination is requested, returns lines of its
+    // output with the time they were received and exit status, which is missing if the command
+    // was stopped
+    fn run_command(
+        &self,
+        mut command: Command,
+    ) -> anyhow::Result<(Option<ExitStatus>, Vec<OutputLine>)> {
+        debug!(command = ?command, "Running cargo");
+        let started = Instant::now();
+        let mut spawn = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .context("Failed to spawn cargo")?;
+
+        // Read output while the command is running, so it never blocks on full pipe
+        let stdout = spawn.stdout.take().context("Failed to get stdout")?;
+        let reader = std::thread::spawn(move || {
+            BufReader::new(stdout)
+                .lines()
+                .map_while(Result::ok)
+                .map(|line| (Instant::now(), line))
+                .collect::<Vec<_>>()
+        });
+
+        let status = loop {
+            if shutdown::is_interrupted() {
+                shutdown::stop_child(&mut spawn, self.shutdown_timeout)?;
+                break None;
+            }
+            if let Some(status) = spawn.try_wait().context("Failed to get status")? {
+                break Some(status);
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        };
+
+        debug!(status = ?status, elapsed_ms = started.elapsed().as_millis() as u64, "Cargo finished");
+
+        let lines = reader
+            .join()
+            .map_err(|_| anyhow::anyhow!("Failed to read cargo output"))?;
+        Ok((status, lines))
+    }
+}
+
+// nextest appends `#<attempt>` to the name of a test that passed or failed on a retry, only
+// the last attempt is reported
+fn split_attempt(name: &str) -> (&str, u32) {
+    name.rsplit_once('#')
+        .and_then(|(name, attempt)| Some((name, attempt.parse().ok()?)))
+        .unwrap_or((name, 1))
+}
+
+// Kind of failure of a nextest command, unknown exit codes are left unclassified
+fn nextest_failure(status: ExitStatus) -> Option<Failure> {
+    match status.code()? {
+        NextestExitCode::TEST_RUN_FAILED | NextestExitCode::SETUP_SCRIPT_FAILED => {
+            Some(Failure::TestsFailed)
         }
+        NextestExitCode::BUILD_FAILED
+        | NextestExitCode::CARGO_METADATA_FAILED
+        | NextestExitCode::TEST_LIST_CREATION_FAILED => Some(Failure::BuildFailed),
+        NextestExitCode::SETUP_ERROR
+        | NextestExitCode::INVALID_FILTERSET
+        | NextestExitCode::EXPERIMENTAL_FEATURE_NOT_ENABLED
+        | NextestExitCode::REQUIRED_VERSION_NOT_MET => Some(Failure::Configuration),
+        _ => None,
+    }
+}
+
+fn command_error(message: &str, status: ExitStatus, failure: Option<Failure>) -> anyhow::Error {
+    let error = anyhow::anyhow!("{message}: {status}");
+    match failure {
+        Some(failure) => failure.error(error),
+        None => error,
+    }
+}
 
-        Ok("target/nextest-knapsack/cargo-metadata.json".into())
+impl Drop for DefaultTestContext {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(self.directory.join(&self.metadata_directory));
     }
 }
 
@@ -177,7 +580,7 @@ mod tests {
     fn test_find_tests_in_directory() {
         let test_directory = "./tests/projects/project";
 
-        let context = DefaultTestContext::new(Path::new(test_directory)).unwrap();
+        let context = DefaultTestContext::new(Path::new(test_directory), &TestSelection::default()).unwrap();
 
         let tests = context.find_tests().unwrap();
 
@@ -218,12 +621,149 @@ mod tests {
         )
     }
 
+    #[test]
+    #[serial]
+    fn should_find_and_run_only_ignored_tests() -> anyhow::Result<()> {
+        let test_directory = "./tests/projects/project";
+
+        let context = DefaultTestContext::new(Path::new(test_directory), &TestSelection::default())?
+            .with_run_ignored(RunIgnored::Only);
+
+        let tests = context.find_tests()?;
+        assert_eq!(
+            tests,
+            vec![Test {
+                package_name: "project".into(),
+                binary_name: "tests".into(),
+                test_name: "ignored_external_test".into()
+            }]
+        );
+        assert_eq!(context.run_tests(&tests)?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_combine_selection_filtersets() {
+        let mut selection = TestSelection::default();
+        assert_eq!(selection.filterset(), None);
+
+        selection.exclude_filtersets = vec!["test(/slow_/)".into()];
+        assert_eq!(
+            selection.filterset(),
+            Some("not ((test(/slow_/)))".into())
+        );
+
+        selection.filtersets = vec!["package(a)".into(), "package(b)".into()];
+        assert_eq!(
+            selection.filterset(),
+            Some("((package(a)) | (package(b))) & not ((test(/slow_/)))".into())
+        );
+
+        assert_eq!(
+            selection.narrowed_to("kind(lib)").filtersets,
+            vec!["(kind(lib)) & (package(a))", "(kind(lib)) & (package(b))"]
+        );
+        assert_eq!(
+            TestSelection::default().narrowed_to("kind(lib)").filtersets,
+            vec!["kind(lib)"]
+        );
+    }
+
+    #[test]
+    fn should_keep_batch_filtersets_with_selection_under_limit() {
+        let tests = (0..2000)
+            .map(|index| Test {
+                package_name: "package".into(),
+                binary_name: "binary".into(),
+                test_name: format!("module::tests::test_{index}"),
+            })
+            .collect::<Vec<_>>();
+        let selection = format!("not test(={})", "x".repeat(MAX_FILTERSET_LENGTH / 2));
+
+        let filtersets = batch_filtersets(&tests, Some(&selection));
+        assert!(filtersets.len() > 2);
+        for filterset in &filtersets {
+            assert!(filterset.len() <= MAX_FILTERSET_LENGTH, "{}", filterset.len());
+            assert!(filterset.ends_with(&format!(" & ({selection})")));
+        }
+        // Full chunk of the batch would go over the limit once combined with the selection
+        let full_chunk = &Test::to_nextest_filtersets(&tests, MAX_FILTERSET_LENGTH)[0];
+        assert!(full_chunk.len() + selection.len() > MAX_FILTERSET_LENGTH);
+    }
+
+    #[test]
+    #[serial]
+    fn should_find_and_run_only_selected_tests() -> anyhow::Result<()> {
+        let test_directory = "./tests/projects/project";
+        let selection = TestSelection {
+            packages: vec!["some_crate".into()],
+            exclude_filtersets: vec!["test(inline)".into()],
+            ..TestSelection::default()
+        };
+
+        let context = DefaultTestContext::new(Path::new(test_directory), &selection)?;
+
+        let crate_external_test = Test {
+            package_name: "some_crate".into(),
+            binary_name: "tests".into(),
+            test_name: "crate_external_test".into(),
+        };
+        assert_eq!(context.find_tests()?, vec![crate_external_test.clone()]);
+
+        // Tests handed out before selection changed are not run
+        let root_external_test = Test {
+            package_name: "project".into(),
+            binary_name: "tests".into(),
+            test_name: "root_external_test".into(),
+        };
+        let results = context.run_tests(&[crate_external_test.clone(), root_external_test])?;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].test, crate_external_test);
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn should_find_and_run_doctests() -> anyhow::Result<()> {
+        let test_directory = "./tests/projects/project";
+        let selection = TestSelection {
+            packages: vec!["doc_crate".into()],
+            doctests: true,
+            ..TestSelection::default()
+        };
+
+        let context = DefaultTestContext::new(Path::new(test_directory), &selection)?;
+
+        let tests = context.find_tests()?;
+        assert_eq!(
+            tests,
+            vec![Test {
+                package_name: "doc_crate".into(),
+                binary_name: DOCTEST_BINARY_NAME.into(),
+                test_name: "doc_crate/src/lib.rs".into()
+            }]
+        );
+        let results = context.run_tests(&tests)?;
+        assert_eq!(results.len(), 1);
+        assert!(results[0].exec_time > 0.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_split_attempt_from_name() {
+        assert_eq!(split_attempt("project::tests$flaky_test#2"), ("project::tests$flaky_test", 2));
+        assert_eq!(split_attempt("project::tests$stable_test"), ("project::tests$stable_test", 1));
+    }
+
     #[test]
     #[serial]
     fn should_run_tests() -> anyhow::Result<()> {
         let test_directory = "./tests/projects/project";
 
-        let context = DefaultTestContext::new(Path::new(test_directory)).unwrap();
+        let context = DefaultTestContext::new(Path::new(test_directory), &TestSelection::default()).unwrap();
 
         let test_1 = Test {
             package_name: "project".into(),
@@ -236,7 +776,7 @@ mod tests {
             test_name: "dir::file::tests::test_in_subdirectory".into(),
         };
 
-        let result = context.run_tests(&vec![test_1, test_2])?;
+        let result = context.run_tests(&[test_1, test_2])?;
 
         assert_eq!(result.len(), 2);
 
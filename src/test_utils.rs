@@ -0,0 +1,73 @@
+use crate::ci_providers::ci_provider_base::CiProvider;
+use crate::models::{Test, TestResult};
+use crate::test_context::TestContext;
+
+pub(crate) struct TestNode {
+    node_index: usize,
+    node_total: usize,
+}
+
+impl TestNode {
+    pub(crate) fn new(node_index: usize, node_total: usize) -> Self {
+        Self {
+            node_index,
+            node_total,
+        }
+    }
+}
+
+impl CiProvider for TestNode {
+    fn get_ci_node_total(&self) -> Option<usize> {
+        Some(self.node_total)
+    }
+
+    fn get_ci_node_index(&self) -> Option<usize> {
+        Some(self.node_index)
+    }
+
+    fn get_ci_node_build_id(&self) -> Option<String> {
+        Some("build_id".into())
+    }
+
+    fn get_commit_hash(&self) -> Option<String> {
+        Some("commit_hash".into())
+    }
+
+    fn is_fixed_queue_split(&self) -> bool {
+        true
+    }
+
+    fn get_branch(&self) -> Option<String> {
+        Some("branch".into())
+    }
+}
+
+// Tests named `pn|bn|<name>`
+pub(crate) struct FixedTests {
+    tests: Vec<Test>,
+}
+
+impl FixedTests {
+    pub(crate) fn new(names: &[&str]) -> Self {
+        Self {
+            tests: names
+                .iter()
+                .map(|name| Test {
+                    package_name: "pn".into(),
+                    binary_name: "bn".into(),
+                    test_name: name.to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TestContext for FixedTests {
+    fn find_tests(&self) -> anyhow::Result<Vec<Test>> {
+        Ok(self.tests.clone())
+    }
+
+    fn run_tests(&self, _tests: &[Test]) -> anyhow::Result<Vec<TestResult>> {
+        Ok(vec![])
+    }
+}
@@ -0,0 +1,172 @@
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Time assumed for tests that were never executed, when there is no history to average
+pub const DEFAULT_TEST_TIME: f64 = 1.0;
+
+// In queues every node takes roughly this many batches, smaller batches at the end keep nodes balanced
+const BATCHES_PER_NODE: f64 = 2.0;
+
+/// Latest known execution time of tests, keyed by Knapsack test file path
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TimingHistory {
+    times: BTreeMap<String, f64>,
+}
+
+impl TimingHistory {
+    /// Missing file is treated as empty history
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read timings [{}]", path.display()))?;
+        let times = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse timings [{}]", path.display()))?;
+        Ok(Self { times })
+    }
+
+    /// Writes the history atomically
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory [{}]", parent.display()))?;
+        }
+        let content =
+            serde_json::to_string_pretty(&self.times).context("Failed to serialize timings")?;
+        // Readers on other nodes never see partially written file
+        let temporary = path.with_extension(format!("tmp-{}", uuid::Uuid::new_v4()));
+        fs::write(&temporary, content)
+            .and_then(|_| fs::rename(&temporary, path))
+            .with_context(|| format!("Failed to write timings [{}]", path.display()))
+    }
+
+    /// Sets time of the test
+    pub fn record(&mut self, path: &str, time: f64) {
+        self.times.insert(path.to_string(), time);
+    }
+
+    /// Latest known time of the test
+    pub fn get(&self, path: &str) -> Option<f64> {
+        self.times.get(path).copied()
+    }
+
+    /// Known time of the test, or average of all known times for new tests
+    pub fn estimate(&self, path: &str) -> f64 {
+        self.get(path).unwrap_or_else(|| {
+            if self.times.is_empty() {
+                DEFAULT_TEST_TIME
+            } else {
+                self.times.values().sum::<f64>() / self.times.len() as f64
+            }
+        })
+    }
+
+    /// Orders paths from the longest to the shortest, ties are ordered by path
+    pub fn longest_first(&self, paths: &[String]) -> Vec<(String, f64)> {
+        let mut timed = paths
+            .iter()
+            .map(|path| (path.clone(), self.estimate(path)))
+            .collect::<Vec<_>>();
+        timed.sort_by(|(a_path, a_time), (b_path, b_time)| {
+            b_time.total_cmp(a_time).then_with(|| a_path.cmp(b_path))
+        });
+        timed
+    }
+}
+
+/// Longest-processing-time bin packing: every item goes to the bin with the lowest total so far.
+/// Deterministic for the same input, so every node computes the same split.
+pub fn partition_longest_first<T>(items: Vec<(T, f64)>, bins: usize) -> Vec<Vec<(T, f64)>> {
+    let mut partition = (0..bins.max(1)).map(|_| vec![]).collect::<Vec<_>>();
+    let mut totals = vec![0.0_f64; partition.len()];
+
+    for (item, time) in items {
+        let (index, _) = totals
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .unwrap();
+        totals[index] += time;
+        partition[index].push((item, time));
+    }
+
+    partition
+}
+
+/// Splits tests (ordered longest first) into queue batches. Each batch takes its share of time
+/// remaining after previous batches, so batches get smaller towards the end of the queue.
+pub fn queue_batches<T>(items: Vec<(T, f64)>, node_total: usize) -> Vec<Vec<(T, f64)>> {
+    let mut remaining_time = items.iter().map(|(_, time)| time).sum::<f64>();
+    let mut batches = vec![];
+    let mut batch = vec![];
+    let mut batch_time = 0.0;
+    let mut target = remaining_time / (node_total.max(1) as f64 * BATCHES_PER_NODE);
+
+    for (item, time) in items {
+        batch_time += time;
+        batch.push((item, time));
+        if batch_time >= target {
+            remaining_time -= batch_time;
+            target = remaining_time / (node_total.max(1) as f64 * BATCHES_PER_NODE);
+            batches.push(std::mem::take(&mut batch));
+            batch_time = 0.0;
+        }
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_estimate_unknown_tests_with_average() {
+        let mut history = TimingHistory::default();
+        assert_eq!(history.estimate("a|b|c"), DEFAULT_TEST_TIME);
+
+        history.record("a|b|c", 2.0);
+        history.record("a|b|d", 4.0);
+        assert_eq!(history.estimate("a|b|c"), 2.0);
+        assert_eq!(history.estimate("a|b|e"), 3.0);
+    }
+
+    #[test]
+    fn should_partition_longest_first() {
+        let mut history = TimingHistory::default();
+        history.record("a", 5.0);
+        history.record("b", 4.0);
+        history.record("c", 3.0);
+        history.record("d", 3.0);
+        history.record("e", 3.0);
+
+        let paths = ["e", "d", "c", "b", "a"].map(String::from);
+        let partition = partition_longest_first(history.longest_first(&paths), 2)
+            .into_iter()
+            .map(|bin| bin.into_iter().map(|(path, _)| path).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        assert_eq!(partition, vec![vec!["a", "d"], vec!["b", "c", "e"]]);
+    }
+
+    #[test]
+    fn should_make_batches_smaller_towards_the_end() {
+        let items = vec![("a", 6.0), ("b", 3.0), ("c", 1.0), ("d", 1.0), ("e", 1.0)];
+
+        let batches = queue_batches(items, 1)
+            .into_iter()
+            .map(|batch| batch.into_iter().map(|(path, _)| path).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            batches,
+            vec![vec!["a"], vec!["b"], vec!["c", "d"], vec!["e"]]
+        );
+    }
+}
@@ -1,2 +1,249 @@
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+
+const BINARY: &str = env!("CARGO_BIN_EXE_cargo-nextest-knapsack");
+
+struct KillOnDrop(Child);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn copy_project(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        if entry.file_name() == "target" {
+            continue;
+        }
+        let target = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_project(&entry.path(), &target);
+        } else {
+            fs::copy(entry.path(), target).unwrap();
+        }
+    }
+}
+
+fn start_server(storage: &Path) -> (KillOnDrop, String) {
+    let mut server = Command::new(BINARY)
+        .args(["serve", "--address", "127.0.0.1:0", "--storage"])
+        .arg(storage)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut line = String::new();
+    BufReader::new(server.stdout.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    let endpoint = line
+        .trim()
+        .strip_prefix("Listening on ")
+        .unwrap()
+        .to_string();
+
+    (KillOnDrop(server), endpoint)
+}
+
+fn journaled_tests(directory: &Path) -> BTreeSet<String> {
+    let mut tests = BTreeSet::new();
+    for entry in fs::read_dir(directory).unwrap() {
+        let path = entry.unwrap().path();
+        if !path
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("journal-")
+        {
+            continue;
+        }
+        for line in fs::read_to_string(path).unwrap().lines() {
+            let entry: Value = serde_json::from_str(line).unwrap();
+            if entry["type"] == "batch" {
+                for file in entry["test_files"].as_array().unwrap() {
+                    tests.insert(file["path"].as_str().unwrap().to_string());
+                }
+            }
+        }
+    }
+    tests
+}
+
+fn all_tests() -> BTreeSet<String> {
+    BTreeSet::from(
+        [
+            "project|project|dir::file::tests::test_in_subdirectory",
+            "project|project|dir::file::tests::test_in_subdirectory_2",
+            "project|project|tests::root_inline_test",
+            "project|tests|root_external_test",
+            "some_crate|some_crate|tests::crate_inline_test",
+            "some_crate|tests|crate_external_test",
+        ]
+        .map(String::from),
+    )
+}
+
 #[test]
-fn e2e_tests() {}
+fn e2e_tests() {
+    let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+    let project = directory.join("project");
+    copy_project(&PathBuf::from("tests/projects/project"), &project);
+
+    let (_server, endpoint) = start_server(&directory.join("server"));
+    let build_id = uuid::Uuid::new_v4().to_string();
+
+    for node_index in 0..2 {
+        let status = Command::new(BINARY)
+            .current_dir(&project)
+            .env("GITHUB_SHA", &build_id)
+            .env("GITHUB_RUN_ID", &build_id)
+            .env("GITHUB_REF", &build_id)
+            .env("KNAPSACK_PRO_CI_NODE_TOTAL", "2")
+            .env("KNAPSACK_PRO_CI_NODE_INDEX", node_index.to_string())
+            .env("KNAPSACK_PRO_TEST_SUITE_TOKEN", "token")
+            .env("KNAPSACK_PRO_ENDPOINT", &endpoint)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    assert_eq!(
+        journaled_tests(&project.join("target/nextest-knapsack")),
+        all_tests()
+    );
+
+    let timings: Value =
+        serde_json::from_str(&fs::read_to_string(directory.join("server/timings.json")).unwrap())
+            .unwrap();
+    assert_eq!(timings.as_object().unwrap().len(), 6);
+
+    fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn e2e_suites_tests() {
+    let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+    let project = directory.join("project");
+    copy_project(&PathBuf::from("tests/projects/project"), &project);
+
+    let (_server, endpoint) = start_server(&directory.join("server"));
+    let build_id = uuid::Uuid::new_v4().to_string();
+
+    let status = Command::new(BINARY)
+        .current_dir(&project)
+        .args([
+            "--suite",
+            "unit=not kind(test)",
+            "--suite",
+            "integration=kind(test)",
+        ])
+        .env("GITHUB_SHA", &build_id)
+        .env("GITHUB_RUN_ID", &build_id)
+        .env("GITHUB_REF", &build_id)
+        .env("KNAPSACK_PRO_CI_NODE_TOTAL", "1")
+        .env("KNAPSACK_PRO_CI_NODE_INDEX", "0")
+        .env("KNAPSACK_PRO_TEST_SUITE_TOKEN_UNIT", "unit-token")
+        .env(
+            "KNAPSACK_PRO_TEST_SUITE_TOKEN_INTEGRATION",
+            "integration-token",
+        )
+        .env("KNAPSACK_PRO_ENDPOINT", &endpoint)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let journals = project.join("target/nextest-knapsack");
+    assert!(journals
+        .join(format!("journal-{build_id}-0-unit.jsonl"))
+        .exists());
+    assert!(journals
+        .join(format!("journal-{build_id}-0-integration.jsonl"))
+        .exists());
+    assert_eq!(journaled_tests(&journals), all_tests());
+
+    fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn e2e_exit_codes() {
+    let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+    let project = directory.join("project");
+    copy_project(&PathBuf::from("tests/projects/project"), &project);
+
+    let (_server, endpoint) = start_server(&directory.join("server"));
+    let run = |endpoint: &str| {
+        let build_id = uuid::Uuid::new_v4().to_string();
+        Command::new(BINARY)
+            .current_dir(&project)
+            .env("GITHUB_SHA", &build_id)
+            .env("GITHUB_RUN_ID", &build_id)
+            .env("GITHUB_REF", &build_id)
+            .env("KNAPSACK_PRO_CI_NODE_TOTAL", "1")
+            .env("KNAPSACK_PRO_CI_NODE_INDEX", "0")
+            .env("KNAPSACK_PRO_TEST_SUITE_TOKEN", "token")
+            .env("KNAPSACK_PRO_ENDPOINT", endpoint)
+            .status()
+            .unwrap()
+            .code()
+    };
+
+    // Nothing listens on port 1
+    assert_eq!(run("http://127.0.0.1:1"), Some(75));
+
+    let tests = project.join("tests/tests.rs");
+    let mut content = fs::read_to_string(&tests).unwrap();
+    content.push_str("\n#[test]\nfn failing_external_test() {\n    assert_eq!(1, 2);\n}\n");
+    fs::write(&tests, &content).unwrap();
+    assert_eq!(run(&endpoint), Some(100));
+    // Time of the failed test is uploaded as well
+    let timings: Value =
+        serde_json::from_str(&fs::read_to_string(directory.join("server/timings.json")).unwrap())
+            .unwrap();
+    assert!(timings.get("project|tests|failing_external_test").is_some());
+
+    content.push_str("\nfn does_not_compile() -> u32 {\n    \"\"\n}\n");
+    fs::write(&tests, &content).unwrap();
+    assert_eq!(run(&endpoint), Some(101));
+
+    fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn simulate_tests() {
+    let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+    let project = directory.join("project");
+    copy_project(&PathBuf::from("tests/projects/project"), &project);
+
+    let output = Command::new(BINARY)
+        .current_dir(&project)
+        .args(["simulate", "--nodes", "3", "--queue-backend", "filesystem"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(output.status.success(), "{stdout}");
+    assert!(stdout.contains("Imbalance"), "{stdout}");
+
+    assert_eq!(
+        journaled_tests(&project.join("target/nextest-knapsack")),
+        all_tests()
+    );
+    let timings: Value = serde_json::from_str(
+        &fs::read_to_string(
+            project.join("target/nextest-knapsack/simulate/filesystem/timings.json"),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(timings.as_object().unwrap().len(), 6);
+
+    fs::remove_dir_all(directory).unwrap();
+}
@@ -0,0 +1,21 @@
+/// Adds one to the number
+///
+/// ```
+/// assert_eq!(doc_crate::add_one(1), 2);
+/// ```
+pub fn add_one(x: i32) -> i32 {
+    x + 1
+}
+
+/// Subtracts one from the number
+///
+/// ```
+/// assert_eq!(doc_crate::subtract_one(1), 0);
+/// ```
+///
+/// ```ignore
+/// assert_eq!(doc_crate::subtract_one(2), 1);
+/// ```
+pub fn subtract_one(x: i32) -> i32 {
+    x - 1
+}
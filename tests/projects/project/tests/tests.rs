@@ -1,4 +1,10 @@
 #[test]
 fn root_external_test() {
     assert_eq!(1, 1);
-}
\ No newline at end of file
+}
+
+#[test]
+#[ignore]
+fn ignored_external_test() {
+    assert_eq!(1, 1);
+}